@@ -9,6 +9,7 @@ pub mod prelude {
     pub use async_trait::async_trait;
     pub use indexmap::{IndexMap, IndexSet};
     pub use itertools::*;
+    pub use serde::{Deserialize, Serialize};
     pub use std::collections::HashMap;
     pub use std::{
         ops::Index,
@@ -0,0 +1,231 @@
+use std::time::{Duration, SystemTime};
+
+use crate::prelude::*;
+
+/// What we remember about a `PublicKeyHash` after having asked the Gateway.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MemoizedKeyHashUsage {
+    /// Used on ledger, this never changes, so never expires.
+    Used,
+    /// Was unused at `checked_at`, might since have been used, so expires
+    /// after the TTL of the `CachingGateway`.
+    Unused { checked_at: SystemTime },
+}
+impl MemoizedKeyHashUsage {
+    fn unused_now() -> Self {
+        Self::Unused {
+            checked_at: SystemTime::now(),
+        }
+    }
+
+    fn from_is_used(is_used: bool) -> Self {
+        if is_used {
+            Self::Used
+        } else {
+            Self::unused_now()
+        }
+    }
+
+    /// `None` if the memoized answer has expired and must be re-fetched.
+    fn is_used(&self, ttl: Duration) -> Option<bool> {
+        match self {
+            Self::Used => Some(true),
+            Self::Unused { checked_at } => {
+                let age = SystemTime::now()
+                    .duration_since(*checked_at)
+                    .unwrap_or_default();
+                (age < ttl).then_some(false)
+            }
+        }
+    }
+}
+
+/// The memo table of a `CachingGateway`, which can be persisted and later
+/// used to restore a `CachingGateway`, making repeated recovery scans fast.
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GatewayMemoTable(pub IndexMap<PublicKeyHash, MemoizedKeyHashUsage>);
+impl GatewayMemoTable {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| e.to_string())
+    }
+
+    pub fn from_json(json: impl AsRef<str>) -> Result<Self> {
+        serde_json::from_str(json.as_ref()).map_err(|e| e.to_string())
+    }
+
+    /// Removes all expired entries, i.e. "unused" answers older than `ttl`.
+    pub fn prune_expired(&mut self, ttl: Duration) {
+        self.0.retain(|_, usage| usage.is_used(ttl).is_some())
+    }
+}
+
+/// A memoizing `Gateway` wrapping another, remembering if each `PublicKeyHash`
+/// has been used or not. A confirmed "used" never expires, whereas "unused"
+/// expires after `ttl`.
+pub struct CachingGateway {
+    gateway: Arc<dyn Gateway>,
+    ttl: Duration,
+    memo_table: RwLock<GatewayMemoTable>,
+}
+
+impl CachingGateway {
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+    pub fn with_memo_table(
+        gateway: Arc<dyn Gateway>,
+        ttl: Duration,
+        mut memo_table: GatewayMemoTable,
+    ) -> Self {
+        memo_table.prune_expired(ttl);
+        Self {
+            gateway,
+            ttl,
+            memo_table: RwLock::new(memo_table),
+        }
+    }
+
+    pub fn new(gateway: Arc<dyn Gateway>, ttl: Duration) -> Self {
+        Self::with_memo_table(gateway, ttl, GatewayMemoTable::default())
+    }
+
+    pub fn with_default_ttl(gateway: Arc<dyn Gateway>) -> Self {
+        Self::new(gateway, Self::DEFAULT_TTL)
+    }
+
+    /// A snapshot of the memo table, suitable for persisting.
+    pub fn memo_table(&self) -> GatewayMemoTable {
+        self.memo_table.read().unwrap().clone()
+    }
+
+    fn memoized(&self, public_key_hash: &PublicKeyHash) -> Option<bool> {
+        self.memo_table
+            .read()
+            .unwrap()
+            .0
+            .get(public_key_hash)
+            .and_then(|usage| usage.is_used(self.ttl))
+    }
+
+    fn memoize(&self, public_key_hash: &PublicKeyHash, is_used: bool) {
+        self.memo_table.write().unwrap().0.insert(
+            public_key_hash.clone(),
+            MemoizedKeyHashUsage::from_is_used(is_used),
+        );
+    }
+}
+
+#[async_trait]
+impl Gateway for CachingGateway {
+    async fn is_key_hash_used(&self, public_key_hash: &PublicKeyHash) -> Result<bool> {
+        if let Some(is_used) = self.memoized(public_key_hash) {
+            return Ok(is_used);
+        }
+        let is_used = self.gateway.is_key_hash_used(public_key_hash).await?;
+        self.memoize(public_key_hash, is_used);
+        Ok(is_used)
+    }
+
+    async fn access_controllers_with_owner_keys(
+        &self,
+        public_key_hashes: &IndexSet<PublicKeyHash>,
+    ) -> Result<IndexSet<OnChainAccessController>> {
+        let access_controllers = self
+            .gateway
+            .access_controllers_with_owner_keys(public_key_hashes)
+            .await?;
+        access_controllers
+            .iter()
+            .flat_map(|ac| ac.owner_key_hashes())
+            .for_each(|h| self.memoize(&h, true));
+        Ok(access_controllers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn key_hash(byte: u8) -> PublicKeyHash {
+        PublicKeyHash::from_hex(hex::encode([byte; 29])).unwrap()
+    }
+
+    fn in_memory_gateway(used: impl IntoIterator<Item = PublicKeyHash>) -> Arc<InMemoryGateway> {
+        Arc::new(InMemoryGateway::new(InMemoryGatewayFixture {
+            used_key_hashes: used.into_iter().collect(),
+            ..Default::default()
+        }))
+    }
+
+    #[tokio::test]
+    async fn unused_is_memoized_until_ttl_expires() {
+        let unused = key_hash(1);
+
+        let inner = in_memory_gateway([]);
+        let gateway = CachingGateway::new(inner.clone(), CachingGateway::DEFAULT_TTL);
+        assert_eq!(gateway.is_key_hash_used(&unused).await, Ok(false));
+        assert_eq!(gateway.is_key_hash_used(&unused).await, Ok(false));
+        assert_eq!(inner.call_count(), 1);
+
+        let inner = in_memory_gateway([]);
+        let gateway = CachingGateway::new(inner.clone(), Duration::ZERO);
+        assert_eq!(gateway.is_key_hash_used(&unused).await, Ok(false));
+        assert_eq!(gateway.is_key_hash_used(&unused).await, Ok(false));
+        assert_eq!(inner.call_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn used_never_expires() {
+        let used = key_hash(1);
+        let inner = in_memory_gateway([used.clone()]);
+        let gateway = CachingGateway::new(inner.clone(), Duration::ZERO);
+        assert_eq!(gateway.is_key_hash_used(&used).await, Ok(true));
+        assert_eq!(gateway.is_key_hash_used(&used).await, Ok(true));
+        assert_eq!(inner.call_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn restoring_memo_table_prunes_expired() {
+        let (used, expired, fresh) = (key_hash(1), key_hash(2), key_hash(3));
+        let memo_table = GatewayMemoTable(IndexMap::from_iter([
+            (used.clone(), MemoizedKeyHashUsage::Used),
+            (
+                expired.clone(),
+                MemoizedKeyHashUsage::Unused {
+                    checked_at: UNIX_EPOCH,
+                },
+            ),
+            (fresh.clone(), MemoizedKeyHashUsage::unused_now()),
+        ]));
+
+        let inner = in_memory_gateway([]);
+        let gateway =
+            CachingGateway::with_memo_table(inner.clone(), CachingGateway::DEFAULT_TTL, memo_table);
+        assert_eq!(
+            gateway.memo_table().0.keys().collect_vec(),
+            vec![&used, &fresh]
+        );
+
+        // answered from the restored memo table, even though `inner` disagrees
+        assert_eq!(gateway.is_key_hash_used(&used).await, Ok(true));
+        assert_eq!(gateway.is_key_hash_used(&fresh).await, Ok(false));
+        assert_eq!(inner.call_count(), 0);
+        assert_eq!(gateway.is_key_hash_used(&expired).await, Ok(false));
+        assert_eq!(inner.call_count(), 1);
+    }
+
+    #[test]
+    fn memo_table_json_roundtrip() {
+        let memo_table = GatewayMemoTable(IndexMap::from_iter([
+            (key_hash(1), MemoizedKeyHashUsage::Used),
+            (
+                key_hash(2),
+                MemoizedKeyHashUsage::Unused {
+                    checked_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                },
+            ),
+        ]));
+        let json = memo_table.to_json().unwrap();
+        assert_eq!(GatewayMemoTable::from_json(json), Ok(memo_table));
+    }
+}
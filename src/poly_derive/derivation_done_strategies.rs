@@ -0,0 +1,249 @@
+use crate::prelude::*;
+
+/// BIP44 style gap limit, done once `gap_limit` consecutive indices after
+/// the highest used one have been scanned, for every factor source and key
+/// space, except factor sources skipped by the user.
+pub struct GapLimit {
+    gap_limit: usize,
+}
+impl GapLimit {
+    pub const BIP44: usize = 20;
+
+    /// # Panics
+    /// Panics if `gap_limit` is zero.
+    pub fn new(gap_limit: usize) -> Self {
+        assert!(gap_limit > 0);
+        Self { gap_limit }
+    }
+}
+impl Default for GapLimit {
+    fn default() -> Self {
+        Self::new(Self::BIP44)
+    }
+}
+
+#[async_trait]
+impl IsDerivationDoneQuery for GapLimit {
+    async fn is_done(&self, progress: &DerivationProgress) -> Result<bool> {
+        let mut scans = progress.active_scans().peekable();
+        Ok(scans.peek().is_some()
+            && scans.all(|scan| scan.consecutive_unused_count() >= self.gap_limit))
+    }
+}
+
+/// Done after a fixed number of rounds, i.e. after deriving `count` batches
+/// per factor source and key space.
+pub struct FixedCount {
+    count: usize,
+}
+impl FixedCount {
+    /// # Panics
+    /// Panics if `count` is zero.
+    pub fn new(count: usize) -> Self {
+        assert!(count > 0);
+        Self { count }
+    }
+}
+
+#[async_trait]
+impl IsDerivationDoneQuery for FixedCount {
+    async fn is_done(&self, progress: &DerivationProgress) -> Result<bool> {
+        Ok(progress.rounds >= self.count)
+    }
+}
+
+/// UI callback asked if the user is done, e.g. "Found 3 accounts, scan more?"
+pub type DerivationDonePrompt = dyn Fn(&DerivationProgress) -> bool + Send + Sync;
+
+/// Defers to the user, who is asked every `rounds_between_prompts` rounds.
+pub struct AskUserIfDone {
+    prompt: Arc<DerivationDonePrompt>,
+    rounds_between_prompts: usize,
+}
+impl AskUserIfDone {
+    /// # Panics
+    /// Panics if `rounds_between_prompts` is zero.
+    pub fn new(prompt: Arc<DerivationDonePrompt>, rounds_between_prompts: usize) -> Self {
+        assert!(rounds_between_prompts > 0);
+        Self {
+            prompt,
+            rounds_between_prompts,
+        }
+    }
+
+    /// Asks the user after every round.
+    pub fn every_round(prompt: Arc<DerivationDonePrompt>) -> Self {
+        Self::new(prompt, 1)
+    }
+}
+
+#[async_trait]
+impl IsDerivationDoneQuery for AskUserIfDone {
+    async fn is_done(&self, progress: &DerivationProgress) -> Result<bool> {
+        if !progress.rounds.is_multiple_of(self.rounds_between_prompts) {
+            return Ok(false);
+        }
+        Ok((self.prompt)(progress))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poly_derive::test_helpers::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Instance at the `n`:th unsecurified index.
+    fn unsecurified_instance(n: usize) -> FactorInstance {
+        account_instance(&factor_source(), KeySpace::Unsecurified, n)
+    }
+
+    fn gateway_with_used(used: impl IntoIterator<Item = FactorInstance>) -> Arc<dyn Gateway> {
+        Arc::new(InMemoryGateway::new(InMemoryGatewayFixture {
+            used_key_hashes: used.into_iter().map(PublicKeyHash::new).collect(),
+            ..Default::default()
+        }))
+    }
+
+    /// Runs OARS, returning the recovered instances and number of rounds.
+    async fn oars_with(
+        gateway: Arc<dyn Gateway>,
+        is_done: Arc<dyn IsDerivationDoneQuery>,
+    ) -> (DerivedFactorInstances, usize) {
+        let batches = Arc::new(AtomicUsize::new(0));
+        let batches_ = batches.clone();
+        let analysis = PolyDerivation::oars(
+            &FactorSources::just(factor_source()),
+            gateway,
+            Arc::new(TestDerivationInteractor),
+            is_done,
+        )
+        .with_progress_listener(Arc::new(move |event| {
+            if let DerivationProgressEvent::BatchStarted { .. } = event {
+                batches_.fetch_add(1, Ordering::SeqCst);
+            }
+        }))
+        .poly_derive()
+        .await
+        .unwrap();
+        (analysis.derived_instances, batches.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn fixed_count_derives_count_rounds() {
+        let (derived, rounds) =
+            oars_with(gateway_with_used([]), Arc::new(FixedCount::new(3))).await;
+        assert_eq!(rounds, 3);
+        assert!(derived.unsecurified_factor_instances().is_empty());
+    }
+
+    // A Device derives a batch of 10 indices per key space per round.
+
+    #[tokio::test]
+    async fn gap_limit_stops_after_gap_of_unused() {
+        let used = unsecurified_instance(12);
+        let (derived, rounds) = oars_with(
+            gateway_with_used([used.clone()]),
+            Arc::new(GapLimit::new(15)),
+        )
+        .await;
+        assert_eq!(rounds, 3);
+        assert_eq!(
+            derived.unsecurified_factor_instances(),
+            IndexSet::<_>::from_iter([FactorInstanceInUnsecurifiedSpace::new(used)])
+        );
+    }
+
+    #[tokio::test]
+    async fn gap_limit_without_any_used() {
+        let (_, rounds) = oars_with(gateway_with_used([]), Arc::new(GapLimit::new(25))).await;
+        assert_eq!(rounds, 3);
+    }
+
+    #[tokio::test]
+    async fn ask_user_stops_when_user_says_so() {
+        let used = [unsecurified_instance(0), unsecurified_instance(12)];
+        let prompt: Arc<DerivationDonePrompt> = Arc::new(|progress| {
+            progress
+                .derived_instances
+                .unsecurified_factor_instances()
+                .len()
+                >= 2
+        });
+        let (derived, rounds) = oars_with(
+            gateway_with_used(used),
+            Arc::new(AskUserIfDone::every_round(prompt)),
+        )
+        .await;
+        assert_eq!(rounds, 2);
+        assert_eq!(derived.unsecurified_factor_instances().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn ask_user_only_every_nth_round() {
+        let prompt: Arc<DerivationDonePrompt> = Arc::new(|_| true);
+        let (_, rounds) = oars_with(
+            gateway_with_used([]),
+            Arc::new(AskUserIfDone::new(prompt, 4)),
+        )
+        .await;
+        assert_eq!(rounds, 4);
+    }
+
+    /// Skips Ledger factor sources from their `skip_from`:th prompt on.
+    struct SkipsLedger {
+        skip_from: usize,
+        ledger_prompts: AtomicUsize,
+        device_prompts: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl DerivationInteractors for SkipsLedger {
+        async fn derive(
+            &self,
+            request: FactorSourceDerivationRequest,
+        ) -> FactorSourceDerivationOutcome {
+            if request.factor_source_kind() == FactorSourceKind::Ledger {
+                let prompt = self.ledger_prompts.fetch_add(1, Ordering::SeqCst) + 1;
+                if prompt >= self.skip_from {
+                    return FactorSourceDerivationOutcome::Skipped;
+                }
+            } else {
+                self.device_prompts.fetch_add(1, Ordering::SeqCst);
+            }
+            TestDerivationInteractor.derive(request).await
+        }
+    }
+
+    #[tokio::test]
+    async fn gap_limit_ignores_factor_source_skipped_mid_scan() {
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let interactor = Arc::new(SkipsLedger {
+            skip_from: 2,
+            ledger_prompts: AtomicUsize::new(0),
+            device_prompts: AtomicUsize::new(0),
+        });
+        let analysis = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            PolyDerivation::oars(
+                &FactorSources::from_iter([factor_source(), ledger.clone()]),
+                gateway_with_used([]),
+                interactor.clone(),
+                // Ledger derives 5 indices per key space per round, so is
+                // skipped before reaching it
+                Arc::new(GapLimit::new(12)),
+            )
+            .poly_derive(),
+        )
+        .await
+        .expect("scan should finish")
+        .unwrap();
+
+        assert_eq!(interactor.device_prompts.load(Ordering::SeqCst), 2);
+        assert_eq!(interactor.ledger_prompts.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            analysis.unscanned_factor_sources,
+            FactorSources::just(ledger)
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use enum_as_inner::EnumAsInner;
+
+use crate::prelude::*;
+
+/// A batch of derivation paths to derive factor instances at, all using
+/// the same factor source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FactorSourceDerivationRequest {
+    pub factor_source: FactorSource,
+    pub derivation_paths: IndexSet<DerivationPath>,
+}
+impl FactorSourceDerivationRequest {
+    /// # Panics
+    /// Panics if any of `derivation_paths` is not for `factor_source`.
+    pub fn new(factor_source: FactorSource, derivation_paths: IndexSet<DerivationPath>) -> Self {
+        assert!(derivation_paths
+            .iter()
+            .all(|p| p.factor_source_id() == factor_source.factor_source_id));
+        Self {
+            factor_source,
+            derivation_paths,
+        }
+    }
+    pub fn factor_source_kind(&self) -> FactorSourceKind {
+        self.factor_source.factor_source_id.factor_source_kind
+    }
+}
+
+/// The outcome of deriving a batch of paths using a single factor source.
+#[derive(Clone, Debug, PartialEq, Eq, EnumAsInner)]
+pub enum FactorSourceDerivationOutcome {
+    /// Derived a factor instance for every path of the request.
+    Derived(FactorInstances),
+    /// The user skipped this factor source, e.g. Ledger not at hand.
+    Skipped,
+    /// Derivation failed, e.g. Ledger disconnected.
+    Failed(String),
+}
+
+/// GUI hooks used to derive factor instances, e.g. Device derives silently
+/// whereas Ledger prompts the user.
+#[async_trait]
+pub trait DerivationInteractors: Send + Sync {
+    /// Derives all paths of `request` using a single factor source, i.e. a
+    /// single prompt for the user covering every path.
+    async fn derive(&self, request: FactorSourceDerivationRequest)
+        -> FactorSourceDerivationOutcome;
+
+    /// Derives using many factor sources at once, by default one at a time.
+    async fn derive_many(
+        &self,
+        requests: Vec<FactorSourceDerivationRequest>,
+    ) -> IndexMap<FactorSourceIDFromHash, FactorSourceDerivationOutcome> {
+        let mut outcomes = IndexMap::new();
+        for request in requests {
+            let factor_source_id = request.factor_source.factor_source_id.clone();
+            outcomes.insert(factor_source_id, self.derive(request).await);
+        }
+        outcomes
+    }
+}
+
+/// Dispatches each request to the interactor registered for the kind of
+/// its factor source.
+#[derive(Default, Clone)]
+pub struct DerivationInteractorsByKind {
+    interactors: IndexMap<FactorSourceKind, Arc<dyn DerivationInteractors>>,
+}
+impl DerivationInteractorsByKind {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `interactor` for `kind`, replacing any previously registered.
+    pub fn register(
+        mut self,
+        kind: FactorSourceKind,
+        interactor: Arc<dyn DerivationInteractors>,
+    ) -> Self {
+        self.interactors.insert(kind, interactor);
+        self
+    }
+}
+
+#[async_trait]
+impl DerivationInteractors for DerivationInteractorsByKind {
+    async fn derive(
+        &self,
+        request: FactorSourceDerivationRequest,
+    ) -> FactorSourceDerivationOutcome {
+        let kind = request.factor_source_kind();
+        let Some(interactor) = self.interactors.get(&kind) else {
+            return FactorSourceDerivationOutcome::Failed(format!(
+                "No interactor registered for factor source kind {:?}",
+                kind
+            ));
+        };
+        interactor.derive(request).await
+    }
+
+    async fn derive_many(
+        &self,
+        requests: Vec<FactorSourceDerivationRequest>,
+    ) -> IndexMap<FactorSourceIDFromHash, FactorSourceDerivationOutcome> {
+        let order = requests
+            .iter()
+            .map(|r| r.factor_source.factor_source_id.clone())
+            .collect_vec();
+        let mut requests_by_kind =
+            IndexMap::<FactorSourceKind, Vec<FactorSourceDerivationRequest>>::new();
+        for request in requests {
+            requests_by_kind
+                .entry(request.factor_source_kind())
+                .or_default()
+                .push(request);
+        }
+        let mut outcomes = IndexMap::new();
+        for (kind, requests) in requests_by_kind {
+            match self.interactors.get(&kind) {
+                Some(interactor) => outcomes.extend(interactor.derive_many(requests).await),
+                None => outcomes.extend(requests.into_iter().map(|r| {
+                    (
+                        r.factor_source.factor_source_id,
+                        FactorSourceDerivationOutcome::Failed(format!(
+                            "No interactor registered for factor source kind {:?}",
+                            kind
+                        )),
+                    )
+                })),
+            }
+        }
+        // Outcomes in the order of the requests, not grouped by kind.
+        order
+            .into_iter()
+            .filter_map(|id| outcomes.swap_remove(&id).map(|outcome| (id, outcome)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poly_derive::test_helpers::*;
+
+    fn request(factor_source: &FactorSource) -> FactorSourceDerivationRequest {
+        FactorSourceDerivationRequest::new(
+            factor_source.clone(),
+            IndexSet::from_iter([
+                account_instance(factor_source, KeySpace::Unsecurified, 0).derivation_path()
+            ]),
+        )
+    }
+
+    #[tokio::test]
+    async fn derive_dispatches_to_interactor_of_kind() {
+        let device = factor_source();
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let device_interactor = Arc::new(RecordingInteractor::default());
+        let ledger_interactor = Arc::new(RecordingInteractor::default());
+        let sut = DerivationInteractorsByKind::new()
+            .register(FactorSourceKind::Device, device_interactor.clone())
+            .register(FactorSourceKind::Ledger, ledger_interactor.clone());
+
+        let outcome = sut.derive(request(&ledger)).await;
+
+        assert_eq!(
+            outcome,
+            FactorSourceDerivationOutcome::Derived(FactorInstances::from_iter([account_instance(
+                &ledger,
+                KeySpace::Unsecurified,
+                0
+            )]))
+        );
+        assert_eq!(
+            ledger_interactor.derive_calls(),
+            vec![ledger.factor_source_id.clone()]
+        );
+        assert!(device_interactor.derive_calls().is_empty());
+        assert_eq!(
+            sut.derive(request(&device)).await,
+            FactorSourceDerivationOutcome::Derived(FactorInstances::from_iter([account_instance(
+                &device,
+                KeySpace::Unsecurified,
+                0
+            )]))
+        );
+        assert_eq!(
+            device_interactor.derive_calls(),
+            vec![device.factor_source_id.clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn derive_fails_for_kind_without_interactor() {
+        let sut = DerivationInteractorsByKind::new()
+            .register(FactorSourceKind::Device, Arc::new(TestDerivationInteractor));
+
+        let outcome = sut
+            .derive(request(&factor_source_with(2, FactorSourceKind::Ledger)))
+            .await;
+
+        assert_eq!(
+            outcome,
+            FactorSourceDerivationOutcome::Failed(
+                "No interactor registered for factor source kind Ledger".to_owned()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn derive_many_has_outcome_for_every_request_in_request_order() {
+        let device = factor_source();
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let arculus = factor_source_with(3, FactorSourceKind::ArculusCard);
+        let device2 = factor_source_with(4, FactorSourceKind::Device);
+        let device_interactor = Arc::new(RecordingInteractor::default());
+        let ledger_interactor = Arc::new(RecordingInteractor::default());
+        let sut = DerivationInteractorsByKind::new()
+            .register(FactorSourceKind::Device, device_interactor.clone())
+            .register(FactorSourceKind::Ledger, ledger_interactor.clone());
+
+        let outcomes = sut
+            .derive_many(vec![
+                request(&ledger),
+                request(&device),
+                request(&arculus),
+                request(&device2),
+            ])
+            .await;
+
+        let derived =
+            |fs: &FactorSource| {
+                FactorSourceDerivationOutcome::Derived(FactorInstances::from_iter([
+                    account_instance(fs, KeySpace::Unsecurified, 0),
+                ]))
+            };
+        assert_eq!(
+            outcomes.into_iter().collect_vec(),
+            vec![
+                (ledger.factor_source_id.clone(), derived(&ledger)),
+                (device.factor_source_id.clone(), derived(&device)),
+                (
+                    arculus.factor_source_id.clone(),
+                    FactorSourceDerivationOutcome::Failed(
+                        "No interactor registered for factor source kind ArculusCard".to_owned()
+                    )
+                ),
+                (device2.factor_source_id.clone(), derived(&device2)),
+            ]
+        );
+        assert_eq!(
+            device_interactor.derive_many_calls(),
+            vec![vec![
+                device.factor_source_id.clone(),
+                device2.factor_source_id.clone()
+            ]]
+        );
+        assert_eq!(
+            ledger_interactor.derive_many_calls(),
+            vec![vec![ledger.factor_source_id.clone()]]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use crate::prelude::*;
+
+/// Summary of `FactorSourceDerivationOutcome`, without the instances.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOutcome {
+    Derived,
+    Skipped,
+    Failed(String),
+}
+impl From<&FactorSourceDerivationOutcome> for BatchOutcome {
+    fn from(value: &FactorSourceDerivationOutcome) -> Self {
+        match value {
+            FactorSourceDerivationOutcome::Derived(_) => Self::Derived,
+            FactorSourceDerivationOutcome::Skipped => Self::Skipped,
+            FactorSourceDerivationOutcome::Failed(error) => Self::Failed(error.clone()),
+        }
+    }
+}
+
+/// Structured progress of a derivation or recovery scan, e.g. for the UI
+/// to display "Found 3 accounts on Ledger A, scanning index 40-60".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DerivationProgressEvent {
+    /// The next index to derive at for `request`, and which of Cache,
+    /// Profile or on-chain decided it.
+    NextIndexResolved {
+        request: DerivationRequestInKeySpace,
+        resolved: ResolvedNextIndex,
+    },
+    /// The factor source was asked to derive at `derivation_paths`.
+    BatchStarted {
+        factor_source_id: FactorSourceIDFromHash,
+        derivation_paths: IndexSet<DerivationPath>,
+    },
+    BatchFinished {
+        factor_source_id: FactorSourceIDFromHash,
+        outcome: BatchOutcome,
+    },
+    InstancesDerived {
+        factor_source_id: FactorSourceIDFromHash,
+        instances: FactorInstances,
+    },
+    /// `lookups` instances were checked against Gateway, `used` of them
+    /// were used on-chain.
+    GatewayLookupsDone { lookups: usize, used: usize },
+    /// Number of accounts or personas found during a recovery scan, in this
+    /// round, a securified entity counts once regardless of its number of
+    /// keys.
+    EntitiesDiscovered {
+        entity_kind: CAP26EntityKind,
+        count: usize,
+    },
+    CacheHit {
+        request: DerivationRequestInKeySpace,
+    },
+    CacheMiss {
+        request: DerivationRequestInKeySpace,
+    },
+}
+
+/// Receives `DerivationProgressEvent`s, implemented by any `Fn` closure,
+/// or use `DerivationProgressChannel` to get events as an async stream.
+pub trait DerivationProgressListener: Send + Sync {
+    fn on_event(&self, event: DerivationProgressEvent);
+}
+
+impl<F: Fn(DerivationProgressEvent) + Send + Sync> DerivationProgressListener for F {
+    fn on_event(&self, event: DerivationProgressEvent) {
+        self(event)
+    }
+}
+
+/// Ignores all events, used when no listener is given.
+pub struct NoProgressListener;
+impl DerivationProgressListener for NoProgressListener {
+    fn on_event(&self, _event: DerivationProgressEvent) {}
+}
+
+/// A listener forwarding all events to an async stream of events.
+pub struct DerivationProgressChannel {
+    sender: tokio::sync::mpsc::UnboundedSender<DerivationProgressEvent>,
+}
+impl DerivationProgressChannel {
+    /// Returns the listener and the receiving end of the stream of events,
+    /// which ends once the listener is dropped.
+    pub fn new() -> (
+        Arc<Self>,
+        tokio::sync::mpsc::UnboundedReceiver<DerivationProgressEvent>,
+    ) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        (Arc::new(Self { sender }), receiver)
+    }
+}
+impl DerivationProgressListener for DerivationProgressChannel {
+    fn on_event(&self, event: DerivationProgressEvent) {
+        // Receiver dropped means nobody is interested in progress anymore.
+        let _ = self.sender.send(event);
+    }
+}
+
+/// Scan progress of a single factor source in a single key space.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct KeySpaceScanProgress {
+    /// All indices derived at.
+    pub scanned_indices: IndexSet<CAP26Index>,
+    /// The indices found used on-chain.
+    pub used_indices: IndexSet<CAP26Index>,
+}
+impl KeySpaceScanProgress {
+    pub fn unused_count(&self) -> usize {
+        self.scanned_indices.len() - self.used_indices.len()
+    }
+
+    /// Number of scanned indices after the highest used one, or all of
+    /// them if none was used.
+    pub fn consecutive_unused_count(&self) -> usize {
+        match self.used_indices.iter().max() {
+            Some(highest_used) => self
+                .scanned_indices
+                .iter()
+                .filter(|i| *i > highest_used)
+                .count(),
+            None => self.scanned_indices.len(),
+        }
+    }
+}
+
+/// Snapshot of the progress of a `PolyDerivation`, used to decide if it is
+/// done, by `IsDerivationDoneQuery`.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct DerivationProgress {
+    /// Number of rounds run.
+    pub rounds: usize,
+    pub derived_instances: DerivedFactorInstances,
+    pub scans: IndexMap<(FactorSourceIDFromHash, KeySpace), KeySpaceScanProgress>,
+    /// Factor sources skipped by the user, they are not asked again, so
+    /// their `scans` never advance.
+    pub skipped: IndexSet<FactorSourceIDFromHash>,
+}
+impl DerivationProgress {
+    /// Scans of factor sources not skipped by the user.
+    pub fn active_scans(&self) -> impl Iterator<Item = &KeySpaceScanProgress> {
+        self.scans
+            .iter()
+            .filter(|((id, _), _)| !self.skipped.contains(id))
+            .map(|(_, scan)| scan)
+    }
+
+    /// Records `derived` instances of which `used` were used on-chain.
+    pub(crate) fn record_scan(&mut self, derived: &FactorInstances, used: &FactorInstances) {
+        for instance in derived.0.iter() {
+            let index = instance.derivation_path().index();
+            let scan = self
+                .scans
+                .entry((instance.factor_source_id(), instance.key_space()))
+                .or_default();
+            scan.scanned_indices.insert(index.clone());
+            if used.0.contains(instance) {
+                scan.used_indices.insert(index);
+            }
+        }
+    }
+}
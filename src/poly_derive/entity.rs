@@ -0,0 +1,320 @@
+use std::{
+    fmt::{Debug, Display},
+    hash::Hash,
+};
+
+use enum_as_inner::EnumAsInner;
+
+use crate::prelude::*;
+
+/// Something of a single CAP26 entity kind, e.g. an account or its address.
+pub trait HasEntityKind {
+    fn entity_kind() -> CAP26EntityKind;
+}
+
+/// Address of an entity, created from the public key of its `veci`.
+pub trait IsEntityAddress:
+    HasEntityKind + Clone + Debug + Display + PartialEq + Eq + Hash + Into<AddressOfAccountOrPersona>
+{
+    fn new(factor_instance: impl Into<FactorInstance>, network_id: NetworkID) -> Self;
+    fn network_id(&self) -> NetworkID;
+    fn try_from_address(address: &AddressOfAccountOrPersona) -> Option<Self>;
+
+    /// Where entities with addresses of this kind are kept in a network of
+    /// `Profile`.
+    fn entities_in(network: &ProfileNetwork) -> &IndexSet<AbstractEntity<Self>>;
+    fn entities_in_mut(network: &mut ProfileNetwork) -> &mut IndexSet<AbstractEntity<Self>>;
+}
+
+impl HasEntityKind for AccountAddress {
+    fn entity_kind() -> CAP26EntityKind {
+        CAP26EntityKind::Account
+    }
+}
+impl IsEntityAddress for AccountAddress {
+    fn new(factor_instance: impl Into<FactorInstance>, network_id: NetworkID) -> Self {
+        Self::new(factor_instance, network_id)
+    }
+    fn network_id(&self) -> NetworkID {
+        self.network_id
+    }
+    fn try_from_address(address: &AddressOfAccountOrPersona) -> Option<Self> {
+        address.as_account().cloned()
+    }
+    fn entities_in(network: &ProfileNetwork) -> &IndexSet<Account> {
+        &network.accounts
+    }
+    fn entities_in_mut(network: &mut ProfileNetwork) -> &mut IndexSet<Account> {
+        &mut network.accounts
+    }
+}
+
+impl HasEntityKind for IdentityAddress {
+    fn entity_kind() -> CAP26EntityKind {
+        CAP26EntityKind::Identity
+    }
+}
+impl IsEntityAddress for IdentityAddress {
+    fn new(factor_instance: impl Into<FactorInstance>, network_id: NetworkID) -> Self {
+        Self::new(factor_instance, network_id)
+    }
+    fn network_id(&self) -> NetworkID {
+        self.network_id
+    }
+    fn try_from_address(address: &AddressOfAccountOrPersona) -> Option<Self> {
+        address.as_identity().cloned()
+    }
+    fn entities_in(network: &ProfileNetwork) -> &IndexSet<Persona> {
+        &network.personas
+    }
+    fn entities_in_mut(network: &mut ProfileNetwork) -> &mut IndexSet<Persona> {
+        &mut network.personas
+    }
+}
+
+/// An entity in Profile, e.g. an `Account` or a `Persona`, which the
+/// derivation flows, `ProfileAnalyzer` and recovery are generic over.
+pub trait Entity: HasEntityKind + Clone + Debug + PartialEq + Eq + Hash {
+    type Address: IsEntityAddress;
+
+    fn address(&self) -> Self::Address;
+    fn set_name(&mut self, name: impl AsRef<str>);
+
+    /// The `veci`, the authentication signing key and, if securified, all
+    /// instances of the matrix.
+    fn all_factor_instances(&self) -> IndexSet<FactorInstance>;
+
+    /// Key used for ROLA, i.e. to prove ownership of the entity to dApps.
+    fn authentication_signing_key(&self) -> Option<FactorInstance>;
+    fn set_authentication_signing_key(&mut self, key: FactorInstance);
+
+    fn as_unsecurified_entity(&self) -> Option<&UnsecurifiedEntity<Self::Address>>;
+    fn as_securified_entity(&self) -> Option<&SecurifiedEntity<Self::Address>>;
+
+    fn from_unsecurified(entity: UnsecurifiedEntity<Self::Address>) -> Self;
+    fn from_securified(entity: SecurifiedEntity<Self::Address>) -> Self;
+
+    fn entities_in(network: &ProfileNetwork) -> &IndexSet<Self>;
+    fn entities_in_mut(network: &mut ProfileNetwork) -> &mut IndexSet<Self>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, EnumAsInner)]
+pub enum AbstractEntity<A> {
+    Unsecurified(UnsecurifiedEntity<A>),
+    Securified(SecurifiedEntity<A>),
+}
+pub type Account = AbstractEntity<AccountAddress>;
+pub type Persona = AbstractEntity<IdentityAddress>;
+
+impl<A: IsEntityAddress> AbstractEntity<A> {
+    pub fn new_unsecurified(
+        instance: FactorInstanceInUnsecurifiedSpace,
+        network_id: NetworkID,
+    ) -> Self {
+        Self::Unsecurified(UnsecurifiedEntity::new(instance, network_id))
+    }
+}
+impl<A: IsEntityAddress> HasEntityKind for AbstractEntity<A> {
+    fn entity_kind() -> CAP26EntityKind {
+        A::entity_kind()
+    }
+}
+impl<A: IsEntityAddress> Entity for AbstractEntity<A> {
+    type Address = A;
+
+    fn address(&self) -> A {
+        match self {
+            Self::Unsecurified(e) => e.address.clone(),
+            Self::Securified(e) => e.address.clone(),
+        }
+    }
+    fn set_name(&mut self, _name: impl AsRef<str>) {
+        // noop
+    }
+    fn all_factor_instances(&self) -> IndexSet<FactorInstance> {
+        let mut instances = match self {
+            Self::Unsecurified(e) => IndexSet::from_iter([e.veci.clone()]),
+            Self::Securified(e) => {
+                let mut instances = e.matrix.all_factor_instances();
+                instances.extend(e.veci.clone());
+                instances
+            }
+        };
+        instances.extend(self.authentication_signing_key());
+        instances
+    }
+    fn authentication_signing_key(&self) -> Option<FactorInstance> {
+        match self {
+            Self::Unsecurified(e) => e.authentication_signing_key.clone(),
+            Self::Securified(e) => e.authentication_signing_key.clone(),
+        }
+    }
+    fn set_authentication_signing_key(&mut self, key: FactorInstance) {
+        match self {
+            Self::Unsecurified(e) => e.authentication_signing_key = Some(key),
+            Self::Securified(e) => e.authentication_signing_key = Some(key),
+        }
+    }
+    fn as_unsecurified_entity(&self) -> Option<&UnsecurifiedEntity<A>> {
+        self.as_unsecurified()
+    }
+    fn as_securified_entity(&self) -> Option<&SecurifiedEntity<A>> {
+        self.as_securified()
+    }
+    fn from_unsecurified(entity: UnsecurifiedEntity<A>) -> Self {
+        Self::Unsecurified(entity)
+    }
+    fn from_securified(entity: SecurifiedEntity<A>) -> Self {
+        Self::Securified(entity)
+    }
+    fn entities_in(network: &ProfileNetwork) -> &IndexSet<Self> {
+        A::entities_in(network)
+    }
+    fn entities_in_mut(network: &mut ProfileNetwork) -> &mut IndexSet<Self> {
+        A::entities_in_mut(network)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct UnsecurifiedEntity<A> {
+    pub address: A,
+    pub veci: FactorInstance,
+    pub authentication_signing_key: Option<FactorInstance>,
+}
+pub type UnsecurifiedAccount = UnsecurifiedEntity<AccountAddress>;
+pub type UnsecurifiedPersona = UnsecurifiedEntity<IdentityAddress>;
+
+impl<A: IsEntityAddress> From<UnsecurifiedEntity<A>> for AbstractEntity<A> {
+    fn from(value: UnsecurifiedEntity<A>) -> Self {
+        Self::Unsecurified(value)
+    }
+}
+impl<A: IsEntityAddress> UnsecurifiedEntity<A> {
+    pub fn new(veci: FactorInstanceInUnsecurifiedSpace, network_id: NetworkID) -> Self {
+        Self {
+            address: A::new(veci.instance(), network_id),
+            veci: veci.instance(),
+            authentication_signing_key: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SecurifiedEntity<A> {
+    pub address: A,
+    pub veci: Option<FactorInstance>,
+    pub matrix: MatrixOfFactorInstances,
+    /// Owner keys on-chain not in `matrix` since their instances are not
+    /// known, e.g. if recovered without all factor sources at hand.
+    pub missing_key_hashes: MatrixOfPublicKeyHashes,
+    pub authentication_signing_key: Option<FactorInstance>,
+}
+pub type SecurifiedAccount = SecurifiedEntity<AccountAddress>;
+pub type SecurifiedPersona = SecurifiedEntity<IdentityAddress>;
+
+impl<A: IsEntityAddress> From<SecurifiedEntity<A>> for AbstractEntity<A> {
+    fn from(value: SecurifiedEntity<A>) -> Self {
+        Self::Securified(value)
+    }
+}
+impl<A: IsEntityAddress> SecurifiedEntity<A> {
+    /// Securifies `unsecurified` with `matrix`, keeping its address and
+    /// authentication signing key, and using its instance as `veci`.
+    pub fn securifying(
+        unsecurified: UnsecurifiedEntity<A>,
+        matrix: MatrixOfFactorInstances,
+    ) -> Self {
+        Self {
+            address: unsecurified.address,
+            veci: Some(unsecurified.veci),
+            missing_key_hashes: MatrixOfPublicKeyHashes::new(vec![], matrix.threshold(), vec![]),
+            matrix,
+            authentication_signing_key: unsecurified.authentication_signing_key,
+        }
+    }
+
+    /// How `new` differs from the matrix of this entity, where any
+    /// `missing_key_hashes` are removed.
+    pub fn diff(&self, new: &MatrixOfFactorInstances) -> MatrixOfFactorInstancesDiff {
+        MatrixOfFactorInstancesDiff {
+            removed_missing_key_hashes: self.missing_key_hashes.all_key_hashes(),
+            ..self.matrix.diff(new)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poly_derive::test_helpers::*;
+
+    /// Creates an entity of kind `E` and securifies it, checking that every
+    /// instance is of the entity kind of `E` and in the expected key space.
+    async fn create_and_securify<E: Entity>(address_hrp: &str) {
+        let device = factor_source();
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let mut profile = Profile::new(
+            FactorSources::from_iter([device.clone(), ledger.clone()]),
+            IndexSet::new(),
+        );
+
+        let entity = new_virtual_unsecurified_entity::<E>(
+            "Satoshi",
+            NetworkID::Mainnet,
+            &device,
+            None,
+            None,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        assert!(entity.address().to_string().starts_with(address_hrp));
+        assert_eq!(
+            profile.get_entity::<E>(&entity.address()),
+            Ok(entity.clone())
+        );
+        let veci = entity
+            .as_unsecurified_entity()
+            .unwrap()
+            .veci
+            .derivation_path();
+        assert_eq!(veci.entity_kind, E::entity_kind());
+        assert_eq!(veci.key_space(), KeySpace::Unsecurified);
+
+        let securified = securify_unsecurified_entity::<E>(
+            entity.address(),
+            MatrixOfFactorSources::new(vec![device, ledger], 2, vec![]),
+            None,
+            None,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(securified.address, entity.address());
+        assert_eq!(
+            profile.get_entity::<E>(&entity.address()),
+            Ok(E::from_securified(securified.clone()))
+        );
+        let matrix_instances = securified.matrix.all_factor_instances();
+        assert_eq!(matrix_instances.len(), 2);
+        for instance in matrix_instances {
+            let path = instance.derivation_path();
+            assert_eq!(path.entity_kind, E::entity_kind());
+            assert_eq!(path.key_space(), KeySpace::Securified);
+        }
+    }
+
+    #[tokio::test]
+    async fn account_is_created_and_securified() {
+        create_and_securify::<Account>(AccountAddress::HRP).await;
+    }
+
+    #[tokio::test]
+    async fn persona_is_created_and_securified() {
+        create_and_securify::<Persona>(IdentityAddress::HRP).await;
+    }
+}
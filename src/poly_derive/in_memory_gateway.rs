@@ -0,0 +1,292 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::prelude::*;
+
+/// Ledger state an `InMemoryGateway` is seeded with, typically loaded from a
+/// JSON or YAML fixture. Entity addresses are written as single key maps,
+/// e.g. `entity_address: { Account: { network_id: Mainnet, public_key_hash: .. } }`.
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InMemoryGatewayFixture {
+    pub used_key_hashes: IndexSet<PublicKeyHash>,
+    pub access_controllers: IndexSet<OnChainAccessController>,
+}
+impl InMemoryGatewayFixture {
+    pub fn from_json(json: impl AsRef<str>) -> Result<Self> {
+        serde_json::from_str(json.as_ref()).map_err(|e| e.to_string())
+    }
+
+    pub fn from_yaml(yaml: impl AsRef<str>) -> Result<Self> {
+        serde_yaml::from_str(yaml.as_ref()).map_err(|e| e.to_string())
+    }
+}
+
+/// Faults an `InMemoryGateway` injects into every call, used to test
+/// derivation and recovery against a slow or unreliable Gateway.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct GatewayFaults {
+    /// Each call is delayed by this duration.
+    pub latency: Option<Duration>,
+    /// If set, every call fails with this error.
+    pub error: Option<String>,
+    /// If set, every n:th call fails, deterministically.
+    pub fail_every_nth_call: Option<usize>,
+}
+impl GatewayFaults {
+    pub fn none() -> Self {
+        Self::default()
+    }
+    pub fn latency(latency: Duration) -> Self {
+        Self {
+            latency: Some(latency),
+            ..Self::default()
+        }
+    }
+    pub fn error(error: impl AsRef<str>) -> Self {
+        Self {
+            error: Some(error.as_ref().to_owned()),
+            ..Self::default()
+        }
+    }
+    pub fn flaky(fail_every_nth_call: usize) -> Self {
+        assert!(fail_every_nth_call > 0);
+        Self {
+            fail_every_nth_call: Some(fail_every_nth_call),
+            ..Self::default()
+        }
+    }
+}
+
+/// A fake `Gateway` with ledger state held in memory, for deterministic tests.
+pub struct InMemoryGateway {
+    fixture: InMemoryGatewayFixture,
+    faults: GatewayFaults,
+    call_count: AtomicUsize,
+}
+
+impl InMemoryGateway {
+    pub fn with_faults(fixture: InMemoryGatewayFixture, faults: GatewayFaults) -> Self {
+        Self {
+            fixture,
+            faults,
+            call_count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn new(fixture: InMemoryGatewayFixture) -> Self {
+        Self::with_faults(fixture, GatewayFaults::none())
+    }
+
+    pub fn empty() -> Self {
+        Self::new(InMemoryGatewayFixture::default())
+    }
+
+    pub fn from_json(json: impl AsRef<str>) -> Result<Self> {
+        InMemoryGatewayFixture::from_json(json).map(Self::new)
+    }
+
+    pub fn from_yaml(yaml: impl AsRef<str>) -> Result<Self> {
+        InMemoryGatewayFixture::from_yaml(yaml).map(Self::new)
+    }
+
+    /// Number of calls made to this gateway, including failed ones.
+    pub fn call_count(&self) -> usize {
+        self.call_count.load(Ordering::SeqCst)
+    }
+
+    async fn simulate_faults(&self) -> Result<()> {
+        let call_number = self.call_count.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Some(latency) = self.faults.latency {
+            tokio::time::sleep(latency).await;
+        }
+        if let Some(error) = self.faults.error.as_ref() {
+            return Err(error.clone());
+        }
+        if let Some(n) = self.faults.fail_every_nth_call {
+            if call_number.is_multiple_of(n) {
+                return Err(format!("Flaky Gateway failed call #{}", call_number));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Gateway for InMemoryGateway {
+    async fn is_key_hash_used(&self, public_key_hash: &PublicKeyHash) -> Result<bool> {
+        self.simulate_faults().await?;
+        let is_used = self.fixture.used_key_hashes.contains(public_key_hash)
+            || self
+                .fixture
+                .access_controllers
+                .iter()
+                .any(|ac| ac.owner_key_hashes().contains(public_key_hash));
+        Ok(is_used)
+    }
+
+    async fn access_controllers_with_owner_keys(
+        &self,
+        public_key_hashes: &IndexSet<PublicKeyHash>,
+    ) -> Result<IndexSet<OnChainAccessController>> {
+        self.simulate_faults().await?;
+        Ok(self
+            .fixture
+            .access_controllers
+            .iter()
+            .filter(|ac| !ac.owner_key_hashes().is_disjoint(public_key_hashes))
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USED: &str = "0101010101010101010101010101010101010101010101010101010101";
+    const THRESHOLD: &str = "0202020202020202020202020202020202020202020202020202020202";
+    const OVERRIDE: &str = "0303030303030303030303030303030303030303030303030303030303";
+    const ACCOUNT: &str = "0404040404040404040404040404040404040404040404040404040404";
+
+    fn key_hash(hex: &str) -> PublicKeyHash {
+        PublicKeyHash::from_hex(hex).unwrap()
+    }
+
+    fn expected_fixture() -> InMemoryGatewayFixture {
+        InMemoryGatewayFixture {
+            used_key_hashes: IndexSet::from_iter([key_hash(USED)]),
+            access_controllers: IndexSet::from_iter([OnChainAccessController {
+                entity_address: AccountAddress {
+                    network_id: NetworkID::Stokenet,
+                    public_key_hash: key_hash(ACCOUNT),
+                }
+                .into(),
+                threshold: 1,
+                threshold_key_hashes: vec![key_hash(THRESHOLD)],
+                override_key_hashes: vec![key_hash(OVERRIDE)],
+            }]),
+        }
+    }
+
+    #[test]
+    fn fixture_from_json() {
+        let json = format!(
+            r#"{{
+                "used_key_hashes": ["{USED}"],
+                "access_controllers": [{{
+                    "entity_address": {{
+                        "Account": {{ "network_id": "Stokenet", "public_key_hash": "{ACCOUNT}" }}
+                    }},
+                    "threshold": 1,
+                    "threshold_key_hashes": ["{THRESHOLD}"],
+                    "override_key_hashes": ["{OVERRIDE}"]
+                }}]
+            }}"#
+        );
+        let fixture = InMemoryGatewayFixture::from_json(&json).unwrap();
+        assert_eq!(fixture, expected_fixture());
+        assert_eq!(
+            InMemoryGatewayFixture::from_json(serde_json::to_string(&fixture).unwrap()),
+            Ok(fixture)
+        );
+    }
+
+    #[test]
+    fn fixture_from_yaml_with_map_form_address() {
+        let yaml = format!(
+            r#"
+used_key_hashes:
+  - "{USED}"
+access_controllers:
+  - entity_address:
+      Account:
+        network_id: Stokenet
+        public_key_hash: "{ACCOUNT}"
+    threshold: 1
+    threshold_key_hashes: ["{THRESHOLD}"]
+    override_key_hashes: ["{OVERRIDE}"]
+"#
+        );
+        let fixture = InMemoryGatewayFixture::from_yaml(&yaml).unwrap();
+        assert_eq!(fixture, expected_fixture());
+        assert_eq!(
+            InMemoryGatewayFixture::from_yaml(serde_yaml::to_string(&fixture).unwrap()),
+            Ok(fixture)
+        );
+    }
+
+    #[test]
+    fn fixture_with_missing_fields_defaults_to_empty() {
+        assert_eq!(
+            InMemoryGatewayFixture::from_yaml("used_key_hashes: []"),
+            Ok(InMemoryGatewayFixture::default())
+        );
+        assert!(InMemoryGatewayFixture::from_json(
+            r#"{ "access_controllers": [{ "entity_address": {}, "threshold": 1,
+                 "threshold_key_hashes": [], "override_key_hashes": [] }] }"#
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_from_fixture() {
+        let gateway = InMemoryGateway::new(expected_fixture());
+        assert_eq!(gateway.is_key_hash_used(&key_hash(USED)).await, Ok(true));
+        assert_eq!(
+            gateway.is_key_hash_used(&key_hash(OVERRIDE)).await,
+            Ok(true)
+        );
+        assert_eq!(
+            gateway.is_key_hash_used(&key_hash(ACCOUNT)).await,
+            Ok(false)
+        );
+        assert_eq!(
+            gateway
+                .access_controllers_with_owner_keys(&IndexSet::from_iter([key_hash(THRESHOLD)]))
+                .await,
+            Ok(expected_fixture().access_controllers)
+        );
+        assert_eq!(gateway.call_count(), 4);
+    }
+
+    #[tokio::test]
+    async fn error_fault_fails_every_call() {
+        let gateway =
+            InMemoryGateway::with_faults(expected_fixture(), GatewayFaults::error("Offline"));
+        assert_eq!(
+            gateway.is_key_hash_used(&key_hash(USED)).await,
+            Err("Offline".to_owned())
+        );
+        assert_eq!(
+            gateway
+                .access_controllers_with_owner_keys(&IndexSet::new())
+                .await,
+            Err("Offline".to_owned())
+        );
+        assert_eq!(gateway.call_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn flaky_fault_fails_every_nth_call() {
+        let gateway = InMemoryGateway::with_faults(expected_fixture(), GatewayFaults::flaky(3));
+        let mut outcomes = Vec::new();
+        for _ in 0..6 {
+            outcomes.push(gateway.is_key_hash_used(&key_hash(USED)).await.is_ok());
+        }
+        assert_eq!(outcomes, vec![true, true, false, true, true, false]);
+        assert_eq!(gateway.is_key_hash_used(&key_hash(USED)).await, Ok(true));
+    }
+
+    #[tokio::test]
+    async fn latency_fault_delays_every_call() {
+        let latency = Duration::from_millis(20);
+        let gateway =
+            InMemoryGateway::with_faults(expected_fixture(), GatewayFaults::latency(latency));
+        let start = std::time::Instant::now();
+        assert_eq!(gateway.is_key_hash_used(&key_hash(USED)).await, Ok(true));
+        assert!(start.elapsed() >= latency);
+    }
+}
@@ -0,0 +1,388 @@
+use crate::prelude::*;
+
+/// How the `KeysCollector` drives the factor sources.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KeysCollectorMode {
+    /// One factor source at a time, silent ones first, each with a single
+    /// "monolith" prompt covering every path needed from it.
+    #[default]
+    Serial,
+    /// The interactors are given all factor sources at once.
+    Parallel,
+}
+
+/// Instances derived by the `KeysCollector`, possibly partial since the
+/// user might have skipped some factor sources.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct KeysCollectorOutcome {
+    pub factor_instances: FactorInstances,
+    /// Factor sources skipped by the user, e.g. Ledger not at hand.
+    pub skipped: IndexSet<FactorSourceIDFromHash>,
+}
+
+/// Collects factor instances at derivation paths from multiple factor
+/// sources, using the derivation interactors.
+pub struct KeysCollector {
+    factor_sources: FactorSources,
+    derivation_paths: IndexMap<FactorSourceIDFromHash, IndexSet<DerivationPath>>,
+    interactors: Arc<dyn DerivationInteractors>,
+    mode: KeysCollectorMode,
+    progress_listener: Arc<dyn DerivationProgressListener>,
+}
+
+impl KeysCollector {
+    pub fn with_mode(
+        factor_sources: FactorSources,
+        derivation_paths: IndexMap<FactorSourceIDFromHash, IndexSet<DerivationPath>>,
+        interactors: Arc<dyn DerivationInteractors>,
+        mode: KeysCollectorMode,
+    ) -> Result<Self> {
+        if let Some(unknown) = derivation_paths
+            .keys()
+            .find(|id| !factor_sources.contains(id))
+        {
+            return Err(format!("Unknown factor source: {:?}", unknown));
+        }
+        Ok(Self {
+            factor_sources,
+            derivation_paths,
+            interactors,
+            mode,
+            progress_listener: Arc::new(NoProgressListener),
+        })
+    }
+
+    pub fn with_progress_listener(
+        mut self,
+        progress_listener: Arc<dyn DerivationProgressListener>,
+    ) -> Self {
+        self.progress_listener = progress_listener;
+        self
+    }
+
+    pub fn new(
+        factor_sources: FactorSources,
+        derivation_paths: IndexMap<FactorSourceIDFromHash, IndexSet<DerivationPath>>,
+        interactors: Arc<dyn DerivationInteractors>,
+    ) -> Result<Self> {
+        Self::with_mode(
+            factor_sources,
+            derivation_paths,
+            interactors,
+            KeysCollectorMode::default(),
+        )
+    }
+
+    /// Groups all `derivation_paths` by factor source, so that each factor
+    /// source is asked to derive only once.
+    pub fn grouping(
+        factor_sources: FactorSources,
+        derivation_paths: IndexSet<DerivationPath>,
+        interactors: Arc<dyn DerivationInteractors>,
+        mode: KeysCollectorMode,
+    ) -> Result<Self> {
+        let derivation_paths = derivation_paths
+            .into_iter()
+            .into_group_map_by(|p| p.factor_source_id())
+            .into_iter()
+            .map(|(k, v)| (k, v.into_iter().collect::<IndexSet<_>>()))
+            .collect();
+        Self::with_mode(factor_sources, derivation_paths, interactors, mode)
+    }
+
+    /// One request per factor source, silent factor sources first.
+    fn requests(&self) -> Vec<FactorSourceDerivationRequest> {
+        self.factor_sources
+            .iter()
+            .filter_map(|f| {
+                self.derivation_paths
+                    .get(&f.factor_source_id)
+                    .filter(|paths| !paths.is_empty())
+                    .map(|paths| FactorSourceDerivationRequest::new(f.clone(), paths.clone()))
+            })
+            .sorted_by_key(|r| !r.factor_source_kind().derives_silently())
+            .collect()
+    }
+
+    fn batch_started(&self, request: &FactorSourceDerivationRequest) {
+        self.progress_listener
+            .on_event(DerivationProgressEvent::BatchStarted {
+                factor_source_id: request.factor_source.factor_source_id.clone(),
+                derivation_paths: request.derivation_paths.clone(),
+            })
+    }
+
+    fn batch_finished(
+        &self,
+        factor_source_id: &FactorSourceIDFromHash,
+        outcome: &FactorSourceDerivationOutcome,
+    ) {
+        self.progress_listener
+            .on_event(DerivationProgressEvent::BatchFinished {
+                factor_source_id: factor_source_id.clone(),
+                outcome: outcome.into(),
+            });
+        if let FactorSourceDerivationOutcome::Derived(instances) = outcome {
+            self.progress_listener
+                .on_event(DerivationProgressEvent::InstancesDerived {
+                    factor_source_id: factor_source_id.clone(),
+                    instances: instances.clone(),
+                })
+        }
+    }
+
+    /// `outcome`, unless it derived instances which are not exactly one per
+    /// requested path of `factor_source_id`, in which case it is `Failed`.
+    fn validated(
+        &self,
+        factor_source_id: &FactorSourceIDFromHash,
+        outcome: FactorSourceDerivationOutcome,
+    ) -> FactorSourceDerivationOutcome {
+        let FactorSourceDerivationOutcome::Derived(instances) = &outcome else {
+            return outcome;
+        };
+        let requested = self
+            .derivation_paths
+            .get(factor_source_id)
+            .cloned()
+            .unwrap_or_default();
+        let derived = instances
+            .0
+            .iter()
+            .map(|fi| fi.derivation_path())
+            .collect::<IndexSet<_>>();
+        let missing = requested.difference(&derived).count();
+        let unrequested = derived.difference(&requested).count();
+        let duplicates = instances.0.len() - derived.len();
+        if missing + unrequested + duplicates == 0 {
+            return outcome;
+        }
+        FactorSourceDerivationOutcome::Failed(format!(
+            "Instances derived by {:?} do not match the request, missing: {}, unrequested: {}, duplicates: {}",
+            factor_source_id, missing, unrequested, duplicates
+        ))
+    }
+
+    async fn outcomes(&self) -> IndexMap<FactorSourceIDFromHash, FactorSourceDerivationOutcome> {
+        let requests = self.requests();
+        match self.mode {
+            KeysCollectorMode::Serial => {
+                let mut outcomes = IndexMap::new();
+                for request in requests {
+                    let factor_source_id = request.factor_source.factor_source_id.clone();
+                    self.batch_started(&request);
+                    let outcome = self.interactors.derive(request).await;
+                    let outcome = self.validated(&factor_source_id, outcome);
+                    self.batch_finished(&factor_source_id, &outcome);
+                    outcomes.insert(factor_source_id, outcome);
+                }
+                outcomes
+            }
+            KeysCollectorMode::Parallel => {
+                requests.iter().for_each(|r| self.batch_started(r));
+                let requested = requests
+                    .iter()
+                    .map(|r| r.factor_source.factor_source_id.clone())
+                    .collect_vec();
+                let mut derived = self.interactors.derive_many(requests).await;
+                let outcomes = requested
+                    .into_iter()
+                    .map(|id| {
+                        let outcome = derived.swap_remove(&id).unwrap_or_else(|| {
+                            FactorSourceDerivationOutcome::Failed(format!(
+                                "No outcome for factor source {:?}",
+                                id
+                            ))
+                        });
+                        let outcome = self.validated(&id, outcome);
+                        (id, outcome)
+                    })
+                    .collect::<IndexMap<_, _>>();
+                outcomes
+                    .iter()
+                    .for_each(|(id, outcome)| self.batch_finished(id, outcome));
+                outcomes
+            }
+        }
+    }
+
+    /// Derives using all factor sources, failing if any of them failed, but
+    /// not if the user skipped some, which are recorded in the outcome.
+    pub async fn derive(&self) -> Result<KeysCollectorOutcome> {
+        let mut outcome = KeysCollectorOutcome::default();
+        for (factor_source_id, derivation_outcome) in self.outcomes().await {
+            match derivation_outcome {
+                FactorSourceDerivationOutcome::Derived(derived) => {
+                    outcome.factor_instances.0.extend(derived.0)
+                }
+                FactorSourceDerivationOutcome::Skipped => {
+                    outcome.skipped.insert(factor_source_id);
+                }
+                FactorSourceDerivationOutcome::Failed(error) => return Err(error),
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poly_derive::test_helpers::*;
+
+    /// Derives like `TestDerivationInteractor`, then tampers with the result.
+    struct TamperingInteractor(fn(&mut IndexSet<FactorInstance>));
+
+    #[async_trait]
+    impl DerivationInteractors for TamperingInteractor {
+        async fn derive(
+            &self,
+            request: FactorSourceDerivationRequest,
+        ) -> FactorSourceDerivationOutcome {
+            let mut instances = request
+                .derivation_paths
+                .into_iter()
+                .map(instance_at)
+                .collect::<IndexSet<_>>();
+            (self.0)(&mut instances);
+            FactorSourceDerivationOutcome::Derived(FactorInstances(instances))
+        }
+    }
+
+    async fn derive(
+        tamper: fn(&mut IndexSet<FactorInstance>),
+        mode: KeysCollectorMode,
+    ) -> Result<KeysCollectorOutcome> {
+        let paths = (0..2)
+            .map(|n| {
+                account_instance(&factor_source(), KeySpace::Unsecurified, n).derivation_path()
+            })
+            .collect();
+        KeysCollector::grouping(
+            FactorSources::just(factor_source()),
+            paths,
+            Arc::new(TamperingInteractor(tamper)),
+            mode,
+        )?
+        .derive()
+        .await
+    }
+
+    #[tokio::test]
+    async fn instances_exactly_at_requested_paths_are_accepted() {
+        for mode in [KeysCollectorMode::Serial, KeysCollectorMode::Parallel] {
+            let outcome = derive(|_| {}, mode).await.unwrap();
+            assert_eq!(outcome.factor_instances.0.len(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn instances_not_matching_requested_paths_fail() {
+        let tamperings: [fn(&mut IndexSet<FactorInstance>); 4] = [
+            // missing
+            |instances| {
+                instances.pop();
+            },
+            // unrequested path
+            |instances| {
+                instances.insert(account_instance(&factor_source(), KeySpace::Securified, 0));
+            },
+            // other factor source
+            |instances| {
+                let other = factor_source_with(2, FactorSourceKind::Device);
+                instances.insert(account_instance(&other, KeySpace::Unsecurified, 0));
+            },
+            // two instances at the same path
+            |instances| {
+                let first = instances.first().unwrap().clone();
+                instances.insert(FactorInstance::new(
+                    first.derivation_path(),
+                    PublicKey { bytes: [0; 32] },
+                ));
+            },
+        ];
+        for tamper in tamperings {
+            for mode in [KeysCollectorMode::Serial, KeysCollectorMode::Parallel] {
+                assert!(derive(tamper, mode)
+                    .await
+                    .unwrap_err()
+                    .starts_with("Instances derived by"));
+            }
+        }
+    }
+
+    /// Ledger, Device, ArculusCard and another Device, in that order.
+    fn mixed_factor_sources() -> Vec<FactorSource> {
+        vec![
+            factor_source_with(2, FactorSourceKind::Ledger),
+            factor_source(),
+            factor_source_with(3, FactorSourceKind::ArculusCard),
+            factor_source_with(4, FactorSourceKind::Device),
+        ]
+    }
+
+    async fn collect_from_mixed(
+        mode: KeysCollectorMode,
+    ) -> (Arc<RecordingInteractor>, KeysCollectorOutcome) {
+        let factor_sources = mixed_factor_sources();
+        let paths = factor_sources
+            .iter()
+            .cartesian_product([KeySpace::Unsecurified, KeySpace::Securified])
+            .map(|(fs, key_space)| account_instance(fs, key_space, 0).derivation_path())
+            .collect();
+        let interactor = Arc::new(RecordingInteractor::default());
+        let outcome = KeysCollector::grouping(
+            FactorSources::from_iter(factor_sources),
+            paths,
+            interactor.clone(),
+            mode,
+        )
+        .unwrap()
+        .derive()
+        .await
+        .unwrap();
+        (interactor, outcome)
+    }
+
+    #[tokio::test]
+    async fn serial_prompts_each_factor_source_once_silent_ones_first() {
+        let [ledger, device, arculus, device2] = mixed_factor_sources().try_into().unwrap();
+
+        let (interactor, outcome) = collect_from_mixed(KeysCollectorMode::Serial).await;
+
+        assert_eq!(
+            interactor.derive_calls(),
+            vec![
+                device.factor_source_id,
+                device2.factor_source_id,
+                ledger.factor_source_id,
+                arculus.factor_source_id,
+            ]
+        );
+        assert!(interactor.derive_many_calls().is_empty());
+        assert_eq!(outcome.factor_instances.0.len(), 8);
+        assert!(outcome.skipped.is_empty());
+    }
+
+    #[tokio::test]
+    async fn parallel_passes_every_factor_source_to_single_derive_many() {
+        let factor_sources = mixed_factor_sources();
+
+        let (interactor, outcome) = collect_from_mixed(KeysCollectorMode::Parallel).await;
+
+        assert!(interactor.derive_calls().is_empty());
+        assert_eq!(interactor.derive_many_calls().len(), 1);
+        assert_eq!(
+            interactor.derive_many_calls()[0]
+                .iter()
+                .cloned()
+                .collect::<IndexSet<_>>(),
+            factor_sources
+                .into_iter()
+                .map(|fs| fs.factor_source_id)
+                .collect::<IndexSet<_>>()
+        );
+        assert_eq!(outcome.factor_instances.0.len(), 8);
+        assert!(outcome.skipped.is_empty());
+    }
+}
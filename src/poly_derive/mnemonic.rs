@@ -0,0 +1,125 @@
+use ed25519_dalek::SigningKey;
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+
+use crate::prelude::*;
+
+type HmacSha512 = Hmac<Sha512>;
+
+/// A BIP39 mnemonic and its BIP39 passphrase, from which the keys of e.g.
+/// a Device or Ledger factor source are derived.
+#[derive(Clone, PartialEq, Eq)]
+pub struct MnemonicWithPassphrase {
+    mnemonic: bip39::Mnemonic,
+    passphrase: String,
+}
+impl std::fmt::Debug for MnemonicWithPassphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // never log the secret
+        f.write_str("MnemonicWithPassphrase(<redacted>)")
+    }
+}
+impl MnemonicWithPassphrase {
+    pub fn new(phrase: impl AsRef<str>, passphrase: impl AsRef<str>) -> Result<Self> {
+        let mnemonic = bip39::Mnemonic::parse(phrase.as_ref()).map_err(|e| e.to_string())?;
+        Ok(Self {
+            mnemonic,
+            passphrase: passphrase.as_ref().to_owned(),
+        })
+    }
+
+    /// The Ed25519 public key at `path`, which must be fully hardened, as
+    /// per SLIP-10.
+    pub fn public_key_at(&self, path: &[u32]) -> PublicKey {
+        let seed = self.mnemonic.to_seed(&self.passphrase);
+        slip10_ed25519_public_key(&seed, path)
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> ([u8; 32], [u8; 32]) {
+    let mut mac = HmacSha512::new_from_slice(key).expect("HMAC takes keys of any size");
+    data.iter().for_each(|d| mac.update(d));
+    let bytes = mac.finalize().into_bytes();
+    let (key, chain_code) = bytes.split_at(32);
+    (key.try_into().unwrap(), chain_code.try_into().unwrap())
+}
+
+/// SLIP-10 derivation of an Ed25519 key, where every index is hardened.
+fn slip10_ed25519_public_key(seed: &[u8], path: &[u32]) -> PublicKey {
+    let (mut key, mut chain_code) = hmac_sha512(b"ed25519 seed", &[seed]);
+    for index in path {
+        let hardened = (index | BIP32_HARDENED).to_be_bytes();
+        (key, chain_code) = hmac_sha512(&chain_code, &[&[0x00], &key, &hardened]);
+    }
+    PublicKey {
+        bytes: SigningKey::from_bytes(&key).verifying_key().to_bytes(),
+    }
+}
+
+impl FactorSourceIDFromHash {
+    /// The CAP26 path of the key hashed to form the factor source ID,
+    /// `m/44H/1022H/365H`.
+    pub const SPECIAL_NODE_PATH: [u32; 3] = [44, 1022, 365];
+
+    /// The ID of the factor source of `kind` with `mnemonic_with_passphrase`,
+    /// i.e. the hash of the public key at the CAP26 special node.
+    pub fn from_mnemonic_with_passphrase(
+        factor_source_kind: FactorSourceKind,
+        mnemonic_with_passphrase: &MnemonicWithPassphrase,
+    ) -> Self {
+        Self {
+            public_key_hash: PublicKeyHash::hashing(
+                mnemonic_with_passphrase.public_key_at(&Self::SPECIAL_NODE_PATH),
+            ),
+            factor_source_kind,
+        }
+    }
+}
+
+impl FactorSource {
+    /// Checks that `mnemonic_with_passphrase` is the secret of this factor
+    /// source, e.g. when importing it from a backup.
+    pub fn verify_mnemonic(&self, mnemonic_with_passphrase: &MnemonicWithPassphrase) -> Result<()> {
+        let id = FactorSourceIDFromHash::from_mnemonic_with_passphrase(
+            self.factor_source_id.factor_source_kind,
+            mnemonic_with_passphrase,
+        );
+        if id != self.factor_source_id {
+            return Err("Mnemonic does not match factor source".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABANDON_ART: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon art";
+
+    #[test]
+    fn slip10_test_vector() {
+        // SLIP-10 ed25519 test vector 1, chain m/0H/1H
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        assert_eq!(
+            hex::encode(slip10_ed25519_public_key(&seed, &[0, 1]).bytes),
+            "1932a5270f335bed617d5b935c80aedb1a35bd9fc1e31acafd5372c30f5c1187"
+        );
+    }
+
+    #[test]
+    fn verify_mnemonic_of_factor_source() {
+        let mnemonic = MnemonicWithPassphrase::new(ABANDON_ART, "").unwrap();
+        let factor_source =
+            FactorSource::new(FactorSourceIDFromHash::from_mnemonic_with_passphrase(
+                FactorSourceKind::Device,
+                &mnemonic,
+            ));
+
+        assert_eq!(factor_source.verify_mnemonic(&mnemonic), Ok(()));
+        assert!(factor_source
+            .verify_mnemonic(&MnemonicWithPassphrase::new(ABANDON_ART, "radix").unwrap())
+            .is_err());
+        assert!(MnemonicWithPassphrase::new("abandon art", "").is_err());
+    }
+}
@@ -1,7 +1,30 @@
+mod caching_gateway;
+mod cancellation_token;
+mod derivation_done_strategies;
+mod derivation_interactors;
+mod derivation_progress;
+mod entity;
+mod in_memory_gateway;
+mod keys_collector;
+mod mnemonic;
 mod new_types;
+mod next_index_resolver;
+#[allow(clippy::module_inception)]
 mod poly_derive;
 mod sargon_types;
+#[cfg(test)]
+mod test_helpers;
 
+pub use caching_gateway::*;
+pub use cancellation_token::*;
+pub use derivation_done_strategies::*;
+pub use derivation_interactors::*;
+pub use derivation_progress::*;
+pub use entity::*;
+pub use in_memory_gateway::*;
+pub use keys_collector::*;
+pub use mnemonic::*;
 pub use new_types::*;
+pub use next_index_resolver::*;
 pub use poly_derive::*;
 pub use sargon_types::*;
@@ -1,16 +1,18 @@
-use std::{net, ops::Range};
+use std::{ops::Range, time::SystemTime};
+
+use enum_as_inner::EnumAsInner;
 
 use __std_iter::Step;
 
 use crate::prelude::*;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum KeySpace {
     Unsecurified,
     Securified,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct DerivationRequestAbstractFactorAbstractIndex<T, U: KeySpaced> {
     abstract_factor: T,
     pub network_id: NetworkID,
@@ -45,7 +47,7 @@ impl<T, U: KeySpaced> DerivationRequestAbstractFactorAbstractIndex<T, U> {
     }
 }
 
-pub type DerivationPathAbstractIndex<U: KeySpaced> =
+pub type DerivationPathAbstractIndex<U> =
     DerivationRequestAbstractFactorAbstractIndex<FactorSourceIDFromHash, U>;
 
 impl<U: KeySpaced> DerivationPathAbstractIndex<U> {
@@ -97,6 +99,18 @@ impl KeySpaced for Range<CAP26Index> {
 pub type DerivationRequestWithRange = DerivationPathAbstractIndex<Range<CAP26Index>>;
 pub type DerivationRequestInKeySpace = DerivationPathAbstractIndex<KeySpace>;
 impl DerivationRequestInKeySpace {
+    /// # Panics
+    /// Panics if `index` is not in the key space of this request.
+    pub fn derivation_path_at(&self, index: CAP26Index) -> DerivationPath {
+        DerivationPath::new_with_factor_source_id(
+            self.factor_source_id(),
+            self.network_id,
+            self.entity_kind,
+            self.key_kind,
+            self.key_space,
+            index,
+        )
+    }
     pub fn new(
         factor_source_id: FactorSourceIDFromHash,
         network_id: NetworkID,
@@ -151,6 +165,14 @@ pub type DerivationRequestWithoutFactorInKeySpace =
     DerivationRequestAbstractFactorAbstractIndex<(), KeySpace>;
 
 impl DerivationRequestWithoutFactorInKeySpace {
+    pub fn new(
+        network_id: NetworkID,
+        entity_kind: CAP26EntityKind,
+        key_kind: CAP26KeyKind,
+        key_space: KeySpace,
+    ) -> Self {
+        Self::abstract_abstract_new((), network_id, entity_kind, key_kind, key_space, key_space)
+    }
     pub fn with_factor_source(self, factor_source: &FactorSource) -> DerivationRequestInKeySpace {
         DerivationRequestInKeySpace::new(
             factor_source.factor_source_id.clone(),
@@ -171,38 +193,123 @@ impl DerivationPath {
         assert_eq!(self.index().key_space(), self.key_space);
         self.key_space
     }
+
+    /// The CAP-26 path `m/44H/1022H/<network>H/<entity>H/<key>H/<index>`,
+    /// every component hardened, the index already is.
+    pub fn components(&self) -> [HDPathValue; 6] {
+        [
+            44,
+            1022,
+            self.network_id.cap26_value(),
+            self.entity_kind.cap26_value(),
+            self.key_kind.cap26_value(),
+            self.index().base_index(),
+        ]
+        .map(|component| component | BIP32_HARDENED)
+    }
 }
 
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct AbstractDerivationRequests(IndexSet<DerivationRequestWithoutFactorInKeySpace>);
+impl FromIterator<DerivationRequestWithoutFactorInKeySpace> for AbstractDerivationRequests {
+    fn from_iter<T: IntoIterator<Item = DerivationRequestWithoutFactorInKeySpace>>(
+        iter: T,
+    ) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
 impl AbstractDerivationRequests {
     pub fn for_each_factor_sources(
         &self,
         factor_sources: FactorSources,
     ) -> IndexSet<DerivationRequestInKeySpace> {
         factor_sources
-            .factor_sources()
             .iter()
             .flat_map(|f| self.0.clone().into_iter().map(|x| x.with_factor_source(f)))
             .collect()
     }
 }
 
-#[derive(Default, Clone, Debug, PartialEq, Eq, Hash)]
-pub struct FactorSources(Vec<FactorSource>);
-impl FactorSources {
-    pub fn from_iter(iter: impl IntoIterator<Item = FactorSource>) -> Self {
-        Self(iter.into_iter().collect())
+/// Factor sources keyed by their ID, in insertion order.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct FactorSources(IndexMap<FactorSourceIDFromHash, FactorSource>);
+impl FromIterator<FactorSource> for FactorSources {
+    fn from_iter<T: IntoIterator<Item = FactorSource>>(iter: T) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|f| (f.factor_source_id.clone(), f))
+                .collect(),
+        )
+    }
+}
+impl<'a> IntoIterator for &'a FactorSources {
+    type Item = &'a FactorSource;
+    type IntoIter = indexmap::map::Values<'a, FactorSourceIDFromHash, FactorSource>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.values()
     }
+}
+impl FactorSources {
     pub fn factor_sources(&self) -> IndexSet<FactorSource> {
-        self.0.clone().into_iter().collect()
+        self.iter().cloned().collect()
     }
     pub fn just(factor_source: FactorSource) -> Self {
-        Self(vec![factor_source])
+        Self::from_iter([factor_source])
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &FactorSource> {
+        self.0.values()
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn get(&self, id: &FactorSourceIDFromHash) -> Option<&FactorSource> {
+        self.0.get(id)
+    }
+    pub fn contains(&self, id: &FactorSourceIDFromHash) -> bool {
+        self.0.contains_key(id)
     }
-    pub fn insert(&mut self, factor_source: FactorSource) {
-        assert!(!self.0.iter().any(|f| f == &factor_source));
-        self.0.push(factor_source);
+    pub fn of_kind(&self, kind: FactorSourceKind) -> impl Iterator<Item = &FactorSource> {
+        self.iter().filter(move |f| f.factor_source_kind() == kind)
+    }
+
+    /// Fails if a factor source with the same ID is already present.
+    pub fn insert(&mut self, factor_source: FactorSource) -> Result<()> {
+        if self.contains(&factor_source.factor_source_id) {
+            return Err(format!(
+                "Factor source already exists: {:?}",
+                factor_source.factor_source_id
+            ));
+        }
+        self.0
+            .insert(factor_source.factor_source_id.clone(), factor_source);
+        Ok(())
+    }
+
+    /// Removes the factor source with `id`, keeping the order of the rest.
+    /// Use `Profile::remove_factor_source` to check that no entity
+    /// references it.
+    pub fn remove(&mut self, id: &FactorSourceIDFromHash) -> Option<FactorSource> {
+        self.0.shift_remove(id)
+    }
+
+    /// Most recently used first, ties keep insertion order.
+    pub fn sorted_by_last_used(&self) -> Vec<FactorSource> {
+        self.iter()
+            .sorted_by_key(|f| std::cmp::Reverse(f.last_used_on()))
+            .cloned()
+            .collect()
+    }
+
+    /// Sets `last_used_on` of the factor sources with `ids` to `now`.
+    pub fn mark_used(&mut self, ids: &IndexSet<FactorSourceIDFromHash>, now: SystemTime) {
+        for id in ids {
+            if let Some(factor_source) = self.0.get_mut(id) {
+                factor_source.metadata.last_used_on = now;
+            }
+        }
     }
 }
 
@@ -251,49 +358,200 @@ impl FactorInstanceInSecurifiedSpace {
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct DerivedFactorInstances {
     unsecurified_factor_instances: IndexSet<FactorInstanceInUnsecurifiedSpace>,
-    securified_matrices_of_factor_instances: IndexSet<MatrixOfFactorInstances>,
+    securified_factor_instances: IndexSet<FactorInstanceInSecurifiedSpace>,
+    /// Matrices of recovered securified accounts and personas, rebuilt from
+    /// their AccessControllers on-chain.
+    securified_matrices_of_factor_instances:
+        IndexMap<AddressOfAccountOrPersona, RecoveredMatrixOfFactorInstances>,
 }
 impl DerivedFactorInstances {
-    pub fn unsecurified_accounts(&self, network_id: NetworkID) -> IndexSet<UnsecurifiedAccount> {
-        self.unsecurified_factor_instances()
-            .into_iter()
-            .map(|fi| UnsecurifiedAccount::new(fi, network_id))
+    fn unsecurified_factor_instances_of(
+        &self,
+        entity_kind: CAP26EntityKind,
+    ) -> impl Iterator<Item = FactorInstanceInUnsecurifiedSpace> + '_ {
+        self.unsecurified_factor_instances
+            .iter()
+            .filter(move |fi| {
+                let path = fi.instance().derivation_path();
+                path.entity_kind == entity_kind && path.key_kind == CAP26KeyKind::T9n
+            })
+            .cloned()
+    }
+
+    fn is_securified(&self, address: impl Into<AddressOfAccountOrPersona>) -> bool {
+        self.securified_matrices_of_factor_instances
+            .contains_key(&address.into())
+    }
+
+    /// The unsecurified instance `address` was created with, if derived.
+    fn veci_of<A: IsEntityAddress>(&self, address: &A) -> Option<FactorInstance> {
+        self.unsecurified_factor_instances
+            .iter()
+            .map(|fi| fi.instance())
+            .find(|fi| A::new(fi.clone(), address.network_id()) == *address)
+    }
+
+    /// Unsecurified entities of kind `E`, excluding those whose `veci` has
+    /// been used to create a securified entity.
+    pub fn unsecurified_entities<E: Entity>(
+        &self,
+        network_id: NetworkID,
+    ) -> IndexSet<UnsecurifiedEntity<E::Address>> {
+        self.unsecurified_factor_instances_of(E::entity_kind())
+            .map(|fi| UnsecurifiedEntity::<E::Address>::new(fi, network_id))
+            .filter(|e| !self.is_securified(e.address.clone()))
+            .collect()
+    }
+
+    pub fn securified_entities<E: Entity>(&self) -> IndexSet<SecurifiedEntity<E::Address>> {
+        self.securified_matrices_of_factor_instances
+            .iter()
+            .filter_map(|(address, recovered)| {
+                let address = E::Address::try_from_address(address)?;
+                Some(SecurifiedEntity {
+                    veci: self.veci_of(&address),
+                    address,
+                    matrix: recovered.matrix.clone(),
+                    missing_key_hashes: recovered.missing.clone(),
+                    authentication_signing_key: None,
+                })
+            })
             .collect()
     }
-    pub fn accounts_unsecurified(&self, network_id: NetworkID) -> IndexSet<Account> {
-        self.unsecurified_accounts(network_id)
+
+    /// Both unsecurified and securified entities of kind `E`.
+    pub fn entities<E: Entity>(&self, network_id: NetworkID) -> IndexSet<E> {
+        self.unsecurified_entities::<E>(network_id)
             .into_iter()
-            .map(Into::into)
+            .map(E::from_unsecurified)
+            .chain(
+                self.securified_entities::<E>()
+                    .into_iter()
+                    .map(E::from_securified),
+            )
             .collect()
     }
 
+    /// Addresses of all unsecurified and securified entities, of any kind,
+    /// unsecurified ones on the network of their instance.
+    pub fn entity_addresses(&self) -> IndexSet<AddressOfAccountOrPersona> {
+        self.unsecurified_factor_instances
+            .iter()
+            .map(|fi| fi.instance())
+            .filter(|fi| fi.derivation_path().key_kind == CAP26KeyKind::T9n)
+            .map(|fi| {
+                let path = fi.derivation_path();
+                match path.entity_kind {
+                    CAP26EntityKind::Account => AccountAddress::new(fi, path.network_id).into(),
+                    CAP26EntityKind::Identity => IdentityAddress::new(fi, path.network_id).into(),
+                }
+            })
+            .filter(|address: &AddressOfAccountOrPersona| !self.is_securified(address.clone()))
+            .chain(self.securified_matrices_of_factor_instances.keys().cloned())
+            .collect()
+    }
+
+    /// Both unsecurified and securified accounts.
+    pub fn accounts(&self, network_id: NetworkID) -> IndexSet<Account> {
+        self.entities(network_id)
+    }
+
     pub fn unsecurified_factor_instances(&self) -> IndexSet<FactorInstanceInUnsecurifiedSpace> {
         self.unsecurified_factor_instances.clone()
     }
 
-    // pub fn account_addresses_of_securified(&self) -> IndexSet<AccountAddress> {
-    //     self.securified_factor_instances
-    //         .iter()
-    //         .map(|f| AccountAddress::new(f.clone(), self.network_id))
-    //         .collect()
-    // }
-    // pub fn all_account_addresses(&self) -> IndexSet<AccountAddress> {
-    //     let mut addresses = IndexSet::new();
-    //     addresses.extend(self.account_addresses_of_unsecurified());
-    //     addresses.extend(self.account_addresses_of_securified());
-    //     addresses
-    // }
+    pub fn securified_factor_instances(&self) -> IndexSet<FactorInstanceInSecurifiedSpace> {
+        self.securified_factor_instances.clone()
+    }
+
+    /// All instances, in both key spaces.
+    pub fn all_factor_instances(&self) -> FactorInstances {
+        FactorInstances::from(
+            self.unsecurified_factor_instances
+                .iter()
+                .map(|fi| fi.instance())
+                .chain(
+                    self.securified_factor_instances
+                        .iter()
+                        .map(|fi| fi.instance()),
+                ),
+        )
+    }
+
+    /// Adds the instances in unsecurified key space, others are ignored.
+    pub fn insert_unsecurified(&mut self, instances: &FactorInstances) {
+        self.unsecurified_factor_instances.extend(
+            instances
+                .0
+                .iter()
+                .filter(|fi| fi.key_space() == KeySpace::Unsecurified)
+                .cloned()
+                .map(FactorInstanceInUnsecurifiedSpace::new),
+        )
+    }
+
+    /// Adds the instances in both key spaces.
+    pub fn insert(&mut self, instances: &FactorInstances) {
+        self.insert_unsecurified(instances);
+        self.securified_factor_instances.extend(
+            instances
+                .0
+                .iter()
+                .filter(|fi| fi.key_space() == KeySpace::Securified)
+                .cloned()
+                .map(FactorInstanceInSecurifiedSpace::new),
+        )
+    }
+
+    /// (Re)builds the matrices of `access_controllers` from the securified
+    /// instances derived so far.
+    pub fn rebuild_matrices(&mut self, access_controllers: &IndexSet<OnChainAccessController>) {
+        let instances = FactorInstances::from(
+            self.securified_factor_instances
+                .iter()
+                .map(|fi| fi.instance()),
+        );
+        for access_controller in access_controllers {
+            self.securified_matrices_of_factor_instances.insert(
+                access_controller.entity_address.clone(),
+                RecoveredMatrixOfFactorInstances::from_access_controller(
+                    access_controller,
+                    &instances,
+                ),
+            );
+        }
+    }
 }
 
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct ProbablyFreeFactorInstances(pub IndexSet<FactorInstance>);
 
-#[derive(Default, Clone, Debug, PartialEq, Eq)]
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FactorInstances(pub IndexSet<FactorInstance>);
 impl FactorInstances {
     pub fn from(iter: impl IntoIterator<Item = FactorInstance>) -> Self {
         Self(iter.into_iter().collect())
     }
+
+    /// The highest index of these instances for each request, requests
+    /// without any instance are not included.
+    pub fn highest_indices(
+        &self,
+        requests: &IndexSet<DerivationRequestInKeySpace>,
+    ) -> IndexMap<DerivationRequestInKeySpace, CAP26Index> {
+        let mut highest = IndexMap::<DerivationRequestInKeySpace, CAP26Index>::new();
+        for instance in self.0.iter() {
+            let request = instance.derivation_in_key_space();
+            if !requests.contains(&request) {
+                continue;
+            }
+            let index = instance.derivation_path().index();
+            if highest.get(&request).is_none_or(|h| index > *h) {
+                highest.insert(request, index);
+            }
+        }
+        highest
+    }
 }
 impl FromIterator<FactorInstance> for FactorInstances {
     fn from_iter<T: IntoIterator<Item = FactorInstance>>(iter: T) -> Self {
@@ -304,11 +562,17 @@ impl FromIterator<FactorInstance> for FactorInstances {
 #[derive(Default, Clone)]
 pub struct OnChainAnalyzer {
     gateway: Option<Arc<dyn Gateway>>,
+    /// Instances found to have been used on-chain by `analyze`.
+    used_instances: Arc<RwLock<FactorInstances>>,
+    /// AccessControllers found by `access_controllers_for`.
+    access_controllers: Arc<RwLock<IndexSet<OnChainAccessController>>>,
 }
 impl OnChainAnalyzer {
     pub fn new(gateway: impl Into<Option<Arc<dyn Gateway>>>) -> Self {
         Self {
             gateway: gateway.into(),
+            used_instances: Arc::new(RwLock::new(FactorInstances::default())),
+            access_controllers: Arc::new(RwLock::new(IndexSet::new())),
         }
     }
 
@@ -319,6 +583,69 @@ impl OnChainAnalyzer {
     pub fn dummy() -> Self {
         Self::new(None)
     }
+
+    pub fn is_dummy(&self) -> bool {
+        self.gateway.is_none()
+    }
+
+    /// Checks each of `instances` against Gateway, returning and remembering
+    /// the ones which have been used on-chain. The dummy analyzer says
+    /// everything is free.
+    pub async fn analyze(&self, instances: &FactorInstances) -> Result<FactorInstances> {
+        let Some(gateway) = self.gateway.as_ref() else {
+            return Ok(FactorInstances::default());
+        };
+        let mut used = IndexSet::new();
+        for instance in instances.0.iter() {
+            if gateway
+                .is_key_hash_used(&PublicKeyHash::new(instance.clone()))
+                .await?
+            {
+                used.insert(instance.clone());
+            }
+        }
+        self.used_instances
+            .write()
+            .unwrap()
+            .0
+            .extend(used.iter().cloned());
+        Ok(FactorInstances(used))
+    }
+
+    /// Queries Gateway for AccessControllers having any of the securified
+    /// `instances` as owner key, remembering all found so far, which are
+    /// returned. The dummy analyzer finds none.
+    pub async fn access_controllers_for(
+        &self,
+        instances: &FactorInstances,
+    ) -> Result<IndexSet<OnChainAccessController>> {
+        let Some(gateway) = self.gateway.as_ref() else {
+            return Ok(IndexSet::new());
+        };
+        let hashes = instances
+            .0
+            .iter()
+            .filter(|fi| fi.key_space() == KeySpace::Securified)
+            .map(|fi| PublicKeyHash::new(fi.clone()))
+            .collect::<IndexSet<_>>();
+        if !hashes.is_empty() {
+            let found = gateway.access_controllers_with_owner_keys(&hashes).await?;
+            self.access_controllers.write().unwrap().extend(found);
+        }
+        Ok(self.access_controllers.read().unwrap().clone())
+    }
+
+    /// The highest index seen used on-chain for each request, requests for
+    /// which no used index has been seen are not included.
+    pub fn highest_used_indices(
+        &self,
+        requests: &IndexSet<DerivationRequestInKeySpace>,
+    ) -> IndexMap<DerivationRequestInKeySpace, CAP26Index> {
+        self.used_instances
+            .read()
+            .unwrap()
+            .highest_indices(requests)
+    }
 }
 
 #[derive(Default, Clone)]
@@ -339,14 +666,108 @@ impl ProfileAnalyzer {
     pub fn dummy() -> Self {
         Self::new(None)
     }
+
+    /// All factor instances of all entities in Profile, empty if no Profile.
+    fn all_factor_instances(&self) -> IndexSet<FactorInstance> {
+        self.profile
+            .as_ref()
+            .map(|p| p.all_factor_instances())
+            .unwrap_or_default()
+    }
+
+    /// The highest index used by any account in Profile for each request,
+    /// requests for which no index is used are not included.
+    pub fn highest_used_indices(
+        &self,
+        requests: &IndexSet<DerivationRequestInKeySpace>,
+    ) -> IndexMap<DerivationRequestInKeySpace, CAP26Index> {
+        FactorInstances::from(self.all_factor_instances()).highest_indices(requests)
+    }
+
+    /// The next free derivation path for each request, i.e. the one after
+    /// the highest index used in Profile, grouped by factor source, so that
+    /// we never reuse an index already used by an account in Profile.
+    pub fn next_derivation_paths_fulfilling(
+        &self,
+        requests: &IndexSet<DerivationRequestInKeySpace>,
+    ) -> Result<IndexMap<FactorSourceIDFromHash, IndexSet<DerivationPath>>> {
+        let highest_used = self.highest_used_indices(requests);
+        let mut paths = IndexMap::<FactorSourceIDFromHash, IndexSet<DerivationPath>>::new();
+        for request in requests {
+            let index = match highest_used.get(request) {
+                Some(highest) => highest
+                    .next()
+                    .ok_or_else(|| format!("No free index left for {:?}", request))?,
+                None => CAP26Index::first_in(request.key_space),
+            };
+            paths
+                .entry(request.factor_source_id())
+                .or_default()
+                .insert(request.derivation_path_at(index));
+        }
+        Ok(paths)
+    }
 }
 
-#[derive(Default, Clone, Debug, PartialEq, Eq)]
-pub struct CachedFactorInstances(pub IndexMap<DerivationRequestInKeySpace, FactorInstances>);
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedFactorInstances(
+    #[serde(with = "indexmap::map::serde_seq")]
+    pub  IndexMap<DerivationRequestInKeySpace, FactorInstances>,
+);
+
+/// Offsets to next derivation entity index to use for a given request, i.e.
+/// the index after the last one derived for it, even if that instance
+/// has since been consumed from the cache.
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FactorInstancesCacheCursors(
+    #[serde(with = "indexmap::map::serde_seq")]
+    pub  IndexMap<DerivationRequestInKeySpace, CAP26Index>,
+);
+impl FactorInstancesCacheCursors {
+    pub fn next_index(&self, request: &DerivationRequestInKeySpace) -> Option<CAP26Index> {
+        self.0.get(request).cloned()
+    }
+
+    /// Moves the cursor of each request of `instances` past the highest
+    /// index of them, never moving a cursor backwards.
+    fn advance_past(&mut self, instances: &FactorInstances) -> Result<()> {
+        let requests = instances
+            .0
+            .iter()
+            .map(|fi| fi.derivation_in_key_space())
+            .collect::<IndexSet<_>>();
+        for (request, highest) in instances.highest_indices(&requests) {
+            let next = highest
+                .next()
+                .ok_or_else(|| format!("No free index left for {:?}", request))?;
+            if self.0.get(&request).is_none_or(|c| next > *c) {
+                self.0.insert(request, next);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The persistable state of a `Cache`, its instances and its cursors.
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheSnapshot {
+    pub factor_instances: CachedFactorInstances,
+    pub cursors: FactorInstancesCacheCursors,
+}
+impl CacheSnapshot {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| e.to_string())
+    }
+
+    pub fn from_json(json: impl AsRef<str>) -> Result<Self> {
+        serde_json::from_str(json.as_ref()).map_err(|e| e.to_string())
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct Cache {
     factor_instances_for_requests: RwLock<CachedFactorInstances>,
+    cursors: RwLock<FactorInstancesCacheCursors>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -377,6 +798,66 @@ impl Cache {
             .cloned()
     }
 
+    pub fn cursors(&self) -> FactorInstancesCacheCursors {
+        self.cursors.try_read().unwrap().clone()
+    }
+
+    /// The next index to derive at for each request, per the cursors,
+    /// requests never derived for are not included.
+    pub fn next_indices(
+        &self,
+        requests: &IndexSet<DerivationRequestInKeySpace>,
+    ) -> IndexMap<DerivationRequestInKeySpace, CAP26Index> {
+        let cursors = self.cursors.try_read().unwrap();
+        requests
+            .iter()
+            .filter_map(|r| cursors.next_index(r).map(|i| (r.clone(), i)))
+            .collect()
+    }
+
+    /// Removes and returns the first cached instance of each request,
+    /// requests without any cached instance are not included.
+    pub fn consume(
+        &self,
+        requests: &IndexSet<DerivationRequestInKeySpace>,
+    ) -> IndexMap<DerivationRequestInKeySpace, FactorInstance> {
+        let mut cached = self.factor_instances_for_requests.try_write().unwrap();
+        let consumed = requests
+            .iter()
+            .filter_map(|r| {
+                cached
+                    .0
+                    .get_mut(r)
+                    .and_then(|fi| fi.0.shift_remove_index(0))
+                    .map(|fi| (r.clone(), fi))
+            })
+            .collect();
+        // emptied requests must not be mistaken for cache hits
+        cached.0.retain(|_, instances| !instances.0.is_empty());
+        consumed
+    }
+
+    /// Advances the cursors past `instances`, without caching them, used for
+    /// instances found to be used, so they are not derived again.
+    pub fn advance_cursors_past(&self, instances: &FactorInstances) -> Result<()> {
+        self.cursors.try_write().unwrap().advance_past(instances)
+    }
+
+    /// Adds newly derived `instances` to the cache and advances the cursors.
+    pub fn insert(&self, instances: FactorInstances) -> Result<()> {
+        self.cursors.try_write().unwrap().advance_past(&instances)?;
+        let mut cached = self.factor_instances_for_requests.try_write().unwrap();
+        for instance in instances.0 {
+            cached
+                .0
+                .entry(instance.derivation_in_key_space())
+                .or_default()
+                .0
+                .insert(instance);
+        }
+        Ok(())
+    }
+
     pub async fn load(
         &self,
         requests: IndexSet<DerivationRequestInKeySpace>,
@@ -401,9 +882,14 @@ impl Cache {
 }
 impl Cache {
     fn with_map(map: IndexMap<DerivationRequestInKeySpace, FactorInstances>) -> Self {
-        Self {
-            factor_instances_for_requests: RwLock::new(CachedFactorInstances(map)),
+        let mut cursors = FactorInstancesCacheCursors::default();
+        for instances in map.values() {
+            cursors.advance_past(instances).unwrap();
         }
+        Self::from_snapshot(CacheSnapshot {
+            factor_instances: CachedFactorInstances(map),
+            cursors,
+        })
     }
     pub fn new(probably_free_factor_instances: ProbablyFreeFactorInstances) -> Self {
         let map = probably_free_factor_instances
@@ -419,14 +905,59 @@ impl Cache {
     pub fn empty() -> Self {
         Self::with_map(IndexMap::default())
     }
+
+    /// Restores a persisted cache, derivation continues where the cursors
+    /// of `snapshot` left off.
+    pub fn from_snapshot(snapshot: CacheSnapshot) -> Self {
+        Self {
+            factor_instances_for_requests: RwLock::new(snapshot.factor_instances),
+            cursors: RwLock::new(snapshot.cursors),
+        }
+    }
+
+    /// A snapshot of the instances and cursors of this cache, for persisting.
+    pub fn snapshot(&self) -> CacheSnapshot {
+        CacheSnapshot {
+            factor_instances: self
+                .factor_instances_for_requests
+                .try_read()
+                .unwrap()
+                .clone(),
+            cursors: self.cursors(),
+        }
+    }
 }
 
-pub trait DerivationInteractors {
-    fn call(&self);
+/// An AccessController on ledger securifying an entity, with the hashes of
+/// the public keys of the factor instances of its roles.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OnChainAccessController {
+    pub entity_address: AddressOfAccountOrPersona,
+    pub threshold: usize,
+    pub threshold_key_hashes: Vec<PublicKeyHash>,
+    pub override_key_hashes: Vec<PublicKeyHash>,
+}
+impl OnChainAccessController {
+    pub fn owner_key_hashes(&self) -> IndexSet<PublicKeyHash> {
+        self.threshold_key_hashes
+            .iter()
+            .chain(self.override_key_hashes.iter())
+            .cloned()
+            .collect()
+    }
 }
 
-pub trait Gateway {
-    fn call(&self);
+#[async_trait]
+pub trait Gateway: Send + Sync {
+    /// Whether any entity on ledger references the `public_key_hash`,
+    /// i.e. if the factor instance it was hashed from has been used.
+    async fn is_key_hash_used(&self, public_key_hash: &PublicKeyHash) -> Result<bool>;
+
+    /// All AccessControllers which has any of `public_key_hashes` as owner key.
+    async fn access_controllers_with_owner_keys(
+        &self,
+        public_key_hashes: &IndexSet<PublicKeyHash>,
+    ) -> Result<IndexSet<OnChainAccessController>>;
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -437,38 +968,76 @@ pub struct IntermediaryDerivationsAndAnalysis {
 
 #[derive(Clone, Debug)]
 pub struct FinalDerivationsFinalAndAnalysis {
+    /// Possibly partial if any factor source was skipped by the user.
     pub derived_instances: DerivedFactorInstances,
     pub cache: Arc<Cache>,
+    /// Factor sources skipped by the user, which were not (fully) scanned.
+    pub unscanned_factor_sources: FactorSources,
+    /// Factor sources which derived any instances or whose cached instances
+    /// were consumed.
+    pub used_factor_sources: IndexSet<FactorSourceIDFromHash>,
+}
+
+/// The result of a `PolyDerivation` which might have been cancelled.
+#[derive(Clone, Debug, EnumAsInner)]
+pub enum PolyDerivationOutcome {
+    Finished(FinalDerivationsFinalAndAnalysis),
+    /// Cancelled at a safe point, with what was derived until then, the
+    /// cache is consistent and can be used to resume later.
+    Cancelled(FinalDerivationsFinalAndAnalysis),
 }
 
 pub type HDPathValue = u32;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum CAP26Index {
     Unsecurified(HDPathValue),
     Securified(HDPathValue),
 }
 impl Step for CAP26Index {
-    fn steps_between(start: &Self, end: &Self) -> Option<usize> {
-        todo!()
+    fn steps_between(start: &Self, end: &Self) -> (usize, Option<usize>) {
+        if start.key_space() != end.key_space() || start > end {
+            return (0, None);
+        }
+        let steps = (end.base_index() - start.base_index()) as usize;
+        (steps, Some(steps))
     }
 
     fn forward_checked(start: Self, count: usize) -> Option<Self> {
-        todo!()
+        HDPathValue::try_from(count)
+            .ok()
+            .and_then(|n| start.base_index().checked_add(n))
+            .map(Self::new)
+            .filter(|i| i.key_space() == start.key_space())
     }
 
     fn backward_checked(start: Self, count: usize) -> Option<Self> {
-        todo!()
+        HDPathValue::try_from(count)
+            .ok()
+            .and_then(|n| start.base_index().checked_sub(n))
+            .map(Self::new)
+            .filter(|i| i.key_space() == start.key_space())
     }
 }
 impl CAP26Index {
     pub fn new(base_index: HDPathValue) -> Self {
-        if base_index <= (BIP32_HARDENED + BIP32_SECURIFIED_HALF) {
+        if base_index < (BIP32_HARDENED + BIP32_SECURIFIED_HALF) {
             Self::Unsecurified(base_index)
         } else {
             Self::Securified(base_index)
         }
     }
+    /// The first index in `key_space`.
+    pub fn first_in(key_space: KeySpace) -> Self {
+        match key_space {
+            KeySpace::Unsecurified => Self::Unsecurified(BIP32_HARDENED),
+            KeySpace::Securified => Self::Securified(BIP32_HARDENED + BIP32_SECURIFIED_HALF),
+        }
+    }
+    /// The index after this one, `None` if it would overflow the key space.
+    pub fn next(&self) -> Option<Self> {
+        Step::forward_checked(self.clone(), 1)
+    }
     pub fn base_index(&self) -> HDPathValue {
         match self {
             CAP26Index::Unsecurified(v) => *v,
@@ -483,43 +1052,137 @@ impl CAP26Index {
     }
 }
 
-pub struct KeysCollector;
-impl KeysCollector {
-    pub fn new(
-        factor_sources: FactorSources,
-        derivation_paths: IndexMap<FactorSourceIDFromHash, IndexSet<DerivationPath>>,
-        interactors: Arc<dyn DerivationInteractors>,
-    ) -> Result<Self> {
-        Ok(Self)
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poly_derive::test_helpers::*;
 
-    pub async fn derive() -> Result<FactorInstances> {
-        Ok(FactorInstances::default())
-    }
-}
+    #[test]
+    fn derivation_path_components_are_cap26_values() {
+        let factor_source = factor_source();
+        let account = DerivationRequestInKeySpace::new(
+            factor_source.factor_source_id.clone(),
+            NetworkID::Stokenet,
+            CAP26EntityKind::Account,
+            CAP26KeyKind::T9n,
+            KeySpace::Unsecurified,
+        )
+        .derivation_path_at(CAP26Index::first_in(KeySpace::Unsecurified));
+        let identity = DerivationRequestInKeySpace::new(
+            factor_source.factor_source_id.clone(),
+            NetworkID::Mainnet,
+            CAP26EntityKind::Identity,
+            CAP26KeyKind::Rola,
+            KeySpace::Securified,
+        )
+        .derivation_path_at(CAP26Index::first_in(KeySpace::Securified).next().unwrap());
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub struct UnsecurifiedAccount {
-    pub address: AccountAddress,
-    pub veci: FactorInstance,
-}
-impl From<UnsecurifiedAccount> for Account {
-    fn from(value: UnsecurifiedAccount) -> Self {
-        Account::Unsecurified(value)
+        let hardened = |component: u32| component | BIP32_HARDENED;
+        assert_eq!(
+            account.components(),
+            [44, 1022, 2, 525, 1460, 0].map(hardened)
+        );
+        assert_eq!(
+            identity.components(),
+            [44, 1022, 1, 618, 1678, BIP32_SECURIFIED_HALF + 1].map(hardened)
+        );
     }
-}
-impl UnsecurifiedAccount {
-    pub fn new(veci: FactorInstanceInUnsecurifiedSpace, network_id: NetworkID) -> Self {
-        Self {
-            address: AccountAddress::new(veci.instance(), network_id),
-            veci: veci.instance(),
-        }
+
+    #[test]
+    fn next_derivation_paths_fulfilling_skips_indices_used_in_profile() {
+        let device = factor_source();
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let unsecurified = Account::new_unsecurified(
+            FactorInstanceInUnsecurifiedSpace::new(account_instance(
+                &device,
+                KeySpace::Unsecurified,
+                2,
+            )),
+            NetworkID::Mainnet,
+        );
+        let veci = account_instance(&device, KeySpace::Unsecurified, 0);
+        let securified = Account::Securified(SecurifiedAccount {
+            address: AccountAddress::new(veci.clone(), NetworkID::Mainnet),
+            veci: Some(veci),
+            matrix: MatrixOfFactorInstances::new(
+                vec![
+                    FactorInstanceInSecurifiedSpace::new(account_instance(
+                        &device,
+                        KeySpace::Securified,
+                        4,
+                    )),
+                    FactorInstanceInSecurifiedSpace::new(account_instance(
+                        &ledger,
+                        KeySpace::Securified,
+                        0,
+                    )),
+                ],
+                2,
+                vec![],
+            ),
+            missing_key_hashes: MatrixOfPublicKeyHashes::new(vec![], 2, vec![]),
+            authentication_signing_key: None,
+        });
+        let profile = Profile::new(
+            FactorSources::from_iter([device.clone(), ledger.clone()]),
+            IndexSet::from_iter([unsecurified, securified]),
+        );
+        let requests = IndexSet::from_iter([
+            account_request(&device, KeySpace::Unsecurified),
+            account_request(&device, KeySpace::Securified),
+            account_request(&ledger, KeySpace::Unsecurified),
+            account_request(&ledger, KeySpace::Securified),
+        ]);
+
+        let paths = ProfileAnalyzer::with_profile(Arc::new(profile))
+            .next_derivation_paths_fulfilling(&requests)
+            .unwrap();
+
+        let path = |fs: &FactorSource, key_space: KeySpace, n: usize| {
+            account_instance(fs, key_space, n).derivation_path()
+        };
+        assert_eq!(
+            paths,
+            IndexMap::<_, _>::from_iter([
+                (
+                    device.factor_source_id.clone(),
+                    IndexSet::<_>::from_iter([
+                        path(&device, KeySpace::Unsecurified, 3),
+                        path(&device, KeySpace::Securified, 5),
+                    ])
+                ),
+                (
+                    ledger.factor_source_id.clone(),
+                    IndexSet::<_>::from_iter([
+                        path(&ledger, KeySpace::Unsecurified, 0),
+                        path(&ledger, KeySpace::Securified, 1),
+                    ])
+                ),
+            ])
+        );
     }
-}
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub struct SecurifiedAccount {
-    pub address: AccountAddress,
-    pub veci: Option<FactorInstance>,
-    pub matrix: MatrixOfFactorInstances,
+    #[test]
+    fn next_derivation_paths_fulfilling_without_profile_starts_at_first_index() {
+        let device = factor_source();
+        let requests = IndexSet::from_iter([
+            account_request(&device, KeySpace::Unsecurified),
+            account_request(&device, KeySpace::Securified),
+        ]);
+
+        let paths = ProfileAnalyzer::dummy()
+            .next_derivation_paths_fulfilling(&requests)
+            .unwrap();
+
+        assert_eq!(
+            paths,
+            IndexMap::<_, _>::from_iter([(
+                device.factor_source_id.clone(),
+                IndexSet::<_>::from_iter([
+                    account_instance(&device, KeySpace::Unsecurified, 0).derivation_path(),
+                    account_instance(&device, KeySpace::Securified, 0).derivation_path(),
+                ])
+            )])
+        );
+    }
 }
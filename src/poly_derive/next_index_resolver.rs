@@ -0,0 +1,236 @@
+use crate::prelude::*;
+
+/// Which source decided the next index to derive at for a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NextIndexSource {
+    /// No source knew of any index, the first index of the key space is used.
+    Default,
+    /// The cursor of the `Cache`, i.e. after the highest index derived.
+    Cache,
+    /// The highest index used by any entity in `Profile`.
+    Profile,
+    /// The highest index seen used on-chain.
+    OnChain,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ResolvedNextIndex {
+    pub index: CAP26Index,
+    pub decided_by: NextIndexSource,
+}
+
+/// Resolves the next index to derive at for requests, by taking the max
+/// of the highest index known by `Cache`, `Profile` and on-chain, each of
+/// which might be missing.
+pub struct NextDerivationIndexResolver<'a> {
+    cache: &'a Cache,
+    profile_analyser: &'a ProfileAnalyzer,
+    onchain_analyser: &'a OnChainAnalyzer,
+}
+
+impl<'a> NextDerivationIndexResolver<'a> {
+    pub fn new(
+        cache: &'a Cache,
+        profile_analyser: &'a ProfileAnalyzer,
+        onchain_analyser: &'a OnChainAnalyzer,
+    ) -> Self {
+        Self {
+            cache,
+            profile_analyser,
+            onchain_analyser,
+        }
+    }
+
+    pub fn resolve(
+        &self,
+        requests: &IndexSet<DerivationRequestInKeySpace>,
+    ) -> Result<IndexMap<DerivationRequestInKeySpace, ResolvedNextIndex>> {
+        let next_by_source = [
+            (NextIndexSource::Cache, self.cache.next_indices(requests)),
+            (
+                NextIndexSource::Profile,
+                Self::next_after(self.profile_analyser.highest_used_indices(requests))?,
+            ),
+            (
+                NextIndexSource::OnChain,
+                Self::next_after(self.onchain_analyser.highest_used_indices(requests))?,
+            ),
+        ];
+
+        Ok(requests
+            .iter()
+            .map(|request| {
+                let resolved = next_by_source
+                    .iter()
+                    .filter_map(|(source, next)| next.get(request).map(|i| (*source, i)))
+                    .fold(
+                        None::<(NextIndexSource, &CAP26Index)>,
+                        |max, (source, i)| match max {
+                            Some((_, m)) if m >= i => max,
+                            _ => Some((source, i)),
+                        },
+                    )
+                    .map(|(decided_by, index)| ResolvedNextIndex {
+                        index: index.clone(),
+                        decided_by,
+                    })
+                    .unwrap_or_else(|| ResolvedNextIndex {
+                        index: CAP26Index::first_in(request.key_space),
+                        decided_by: NextIndexSource::Default,
+                    });
+                (request.clone(), resolved)
+            })
+            .collect())
+    }
+
+    fn next_after(
+        highest: IndexMap<DerivationRequestInKeySpace, CAP26Index>,
+    ) -> Result<IndexMap<DerivationRequestInKeySpace, CAP26Index>> {
+        highest
+            .into_iter()
+            .map(|(request, highest)| {
+                highest
+                    .next()
+                    .map(|next| (request.clone(), next))
+                    .ok_or_else(|| format!("No free index left for {:?}", request))
+            })
+            .collect()
+    }
+
+    /// The derivation paths at `count(request)` consecutive indices from
+    /// each `resolved` index, grouped by factor source. Fewer if the key
+    /// space ends.
+    pub fn derivation_paths(
+        resolved: &IndexMap<DerivationRequestInKeySpace, ResolvedNextIndex>,
+        count: impl Fn(&DerivationRequestInKeySpace) -> usize,
+    ) -> IndexMap<FactorSourceIDFromHash, IndexSet<DerivationPath>> {
+        let mut paths = IndexMap::<FactorSourceIDFromHash, IndexSet<DerivationPath>>::new();
+        for (request, resolved) in resolved {
+            paths.entry(request.factor_source_id()).or_default().extend(
+                std::iter::successors(Some(resolved.index.clone()), |i| i.next())
+                    .take(count(request))
+                    .map(|i| request.derivation_path_at(i)),
+            );
+        }
+        paths
+    }
+
+    /// The next derivation path for each request, grouped by factor source.
+    pub fn next_derivation_paths(
+        &self,
+        requests: &IndexSet<DerivationRequestInKeySpace>,
+    ) -> Result<IndexMap<FactorSourceIDFromHash, IndexSet<DerivationPath>>> {
+        Ok(Self::derivation_paths(&self.resolve(requests)?, |_| 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poly_derive::test_helpers::*;
+
+    fn index(n: usize) -> CAP26Index {
+        std::iter::Step::forward(CAP26Index::first_in(KeySpace::Unsecurified), n)
+    }
+
+    fn instance(n: usize) -> FactorInstance {
+        account_instance(&factor_source(), KeySpace::Unsecurified, n)
+    }
+
+    fn resolved(
+        cache: &Cache,
+        profile_analyser: &ProfileAnalyzer,
+        onchain_analyser: &OnChainAnalyzer,
+    ) -> ResolvedNextIndex {
+        let request = account_request(&factor_source(), KeySpace::Unsecurified);
+        NextDerivationIndexResolver::new(cache, profile_analyser, onchain_analyser)
+            .resolve(&IndexSet::from_iter([request.clone()]))
+            .unwrap()
+            .swap_remove(&request)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn highest_of_cache_profile_and_onchain_decides() {
+        let cache = Cache::default();
+        let mut profile_analyser = ProfileAnalyzer::dummy();
+        let mut onchain_analyser = OnChainAnalyzer::dummy();
+        let resolve = |p: &ProfileAnalyzer, o: &OnChainAnalyzer| resolved(&cache, p, o);
+
+        assert_eq!(
+            resolve(&profile_analyser, &onchain_analyser),
+            ResolvedNextIndex {
+                index: index(0),
+                decided_by: NextIndexSource::Default
+            }
+        );
+
+        cache.insert(FactorInstances::from([instance(2)])).unwrap();
+        assert_eq!(
+            resolve(&profile_analyser, &onchain_analyser),
+            ResolvedNextIndex {
+                index: index(3),
+                decided_by: NextIndexSource::Cache
+            }
+        );
+
+        let veci = FactorInstanceInUnsecurifiedSpace::new(instance(5));
+        profile_analyser = ProfileAnalyzer::with_profile(Arc::new(Profile::new(
+            FactorSources::just(factor_source()),
+            IndexSet::from_iter([Account::new_unsecurified(veci, NetworkID::Mainnet)]),
+        )));
+        assert_eq!(
+            resolve(&profile_analyser, &onchain_analyser),
+            ResolvedNextIndex {
+                index: index(6),
+                decided_by: NextIndexSource::Profile
+            }
+        );
+
+        onchain_analyser =
+            OnChainAnalyzer::with_gateway(Arc::new(InMemoryGateway::new(InMemoryGatewayFixture {
+                used_key_hashes: IndexSet::from_iter([PublicKeyHash::new(instance(9))]),
+                ..Default::default()
+            })));
+        onchain_analyser
+            .analyze(&FactorInstances::from([instance(9)]))
+            .await
+            .unwrap();
+        assert_eq!(
+            resolve(&profile_analyser, &onchain_analyser),
+            ResolvedNextIndex {
+                index: index(10),
+                decided_by: NextIndexSource::OnChain
+            }
+        );
+    }
+
+    #[test]
+    fn next_index_continues_at_cursor_of_restored_cache_snapshot() {
+        let cache = Cache::default();
+        // derived and consumed indices 0..=2, so nothing is cached but the
+        // cursor is past them
+        cache
+            .insert(FactorInstances::from((0..3).map(instance)))
+            .unwrap();
+        let request = account_request(&factor_source(), KeySpace::Unsecurified);
+        for _ in 0..3 {
+            cache.consume(&IndexSet::from_iter([request.clone()]));
+        }
+        let json = cache.snapshot().to_json().unwrap();
+
+        let restored = Cache::from_snapshot(CacheSnapshot::from_json(json).unwrap());
+        assert_eq!(restored.snapshot(), cache.snapshot());
+        assert_eq!(
+            resolved(
+                &restored,
+                &ProfileAnalyzer::dummy(),
+                &OnChainAnalyzer::dummy()
+            ),
+            ResolvedNextIndex {
+                index: index(3),
+                decided_by: NextIndexSource::Cache
+            }
+        );
+    }
+}
@@ -1,8 +1,3 @@
-#![allow(unused)]
-#![allow(unused_variables)]
-
-use std::net;
-
 use crate::prelude::*;
 
 pub enum PolyDeriveRequestKind {
@@ -10,64 +5,198 @@ pub enum PolyDeriveRequestKind {
     /// Assumes `Mainnet`
     OARS { factor_sources: FactorSources },
 
-    /// Manual Account Recovery Scan
+    /// Manual Account Recovery Scan (MARS), or its persona counterpart.
     /// Done using a single FactorSource
-    MARS {
+    ManualRecoveryScan {
+        entity_kind: CAP26EntityKind,
         factor_source: FactorSource,
         network_id: NetworkID,
     },
 
-    /// PreDerive FactorInstances for new FactorSource
-    PreDeriveInstancesForNewFactorSource { factor_source: FactorSource },
+    /// PreDerive FactorInstances for new FactorSource, a batch for every
+    /// request, which are kept in the cache
+    PreDeriveInstancesForNewFactorSource {
+        factor_source: FactorSource,
+        network_id: NetworkID,
+    },
 
-    /// New Virtual Unsecurified Account
-    NewVirtualUnsecurifiedAccount {
+    /// New Virtual Unsecurified Account or Persona
+    NewVirtualUnsecurifiedEntity {
+        entity_kind: CAP26EntityKind,
         network_id: NetworkID,
         factor_source: FactorSource,
     },
 
-    /// Securify unsecurified Account
-    SecurifyUnsecurifiedAccount {
-        unsecurified_account: UnsecurifiedAccount,
+    /// Securify unsecurified Account or Persona
+    SecurifyUnsecurifiedEntity {
+        address: AddressOfAccountOrPersona,
         matrix_of_factor_sources: MatrixOfFactorSources,
     },
 
-    /// Securify unsecurified Account
-    UpdateSecurifiedAccount {
-        securified_account: SecurifiedAccount,
+    /// Update the matrix of a securified Account or Persona
+    UpdateSecurifiedEntity {
+        address: AddressOfAccountOrPersona,
+        matrix_of_factor_instances: MatrixOfFactorInstances,
         matrix_of_factor_sources: MatrixOfFactorSources,
+        reuse_policy: FactorInstanceReusePolicy,
+    },
+
+    /// Add a ROLA key to an Account or Persona, in the key space matching
+    /// its securification state.
+    AddAuthenticationSigningKey {
+        address: AddressOfAccountOrPersona,
+        key_space: KeySpace,
+        factor_source: FactorSource,
     },
 }
+
+/// If instances of factor sources kept in an updated matrix are reused, or
+/// if new instances are derived for all factor sources.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FactorInstanceReusePolicy {
+    /// Factor sources kept in the matrix keep their instance.
+    #[default]
+    ReuseExisting,
+    /// New instances are derived for every factor source of the matrix.
+    AlwaysDeriveNew,
+}
+
 impl PolyDeriveRequestKind {
+    /// If this is a recovery scan, which derives a batch of new indices
+    /// every round, rather than loading instances from the cache.
+    pub fn is_recovery_scan(&self) -> bool {
+        matches!(self, Self::OARS { .. } | Self::ManualRecoveryScan { .. })
+    }
+
+    /// If this only fills the cache, without consuming any instances.
+    pub fn is_pre_derive(&self) -> bool {
+        matches!(self, Self::PreDeriveInstancesForNewFactorSource { .. })
+    }
+
+    pub fn requests(&self) -> AbstractDerivationRequests {
+        let t9n = |network_id, entity_kind, key_space| {
+            DerivationRequestWithoutFactorInKeySpace::new(
+                network_id,
+                entity_kind,
+                CAP26KeyKind::T9n,
+                key_space,
+            )
+        };
+        let both_key_spaces = |network_id, entity_kind| {
+            AbstractDerivationRequests::from_iter([
+                t9n(network_id, entity_kind, KeySpace::Unsecurified),
+                t9n(network_id, entity_kind, KeySpace::Securified),
+            ])
+        };
+        let securified = |address: &AddressOfAccountOrPersona| {
+            AbstractDerivationRequests::from_iter([t9n(
+                address.network_id(),
+                address.entity_kind(),
+                KeySpace::Securified,
+            )])
+        };
+        match self {
+            Self::OARS { .. } => both_key_spaces(NetworkID::Mainnet, CAP26EntityKind::Account),
+            Self::ManualRecoveryScan {
+                entity_kind,
+                network_id,
+                ..
+            } => both_key_spaces(*network_id, *entity_kind),
+            Self::PreDeriveInstancesForNewFactorSource {
+                factor_source,
+                network_id,
+            } => {
+                let key_kinds = if factor_source
+                    .factor_source_id
+                    .factor_source_kind
+                    .capabilities()
+                    .allowed_for_rola
+                {
+                    vec![CAP26KeyKind::T9n, CAP26KeyKind::Rola]
+                } else {
+                    vec![CAP26KeyKind::T9n]
+                };
+                [CAP26EntityKind::Account, CAP26EntityKind::Identity]
+                    .into_iter()
+                    .cartesian_product(key_kinds)
+                    .cartesian_product([KeySpace::Unsecurified, KeySpace::Securified])
+                    .map(|((entity_kind, key_kind), key_space)| {
+                        DerivationRequestWithoutFactorInKeySpace::new(
+                            *network_id,
+                            entity_kind,
+                            key_kind,
+                            key_space,
+                        )
+                    })
+                    .collect()
+            }
+            Self::NewVirtualUnsecurifiedEntity {
+                entity_kind,
+                network_id,
+                ..
+            } => AbstractDerivationRequests::from_iter([t9n(
+                *network_id,
+                *entity_kind,
+                KeySpace::Unsecurified,
+            )]),
+            Self::SecurifyUnsecurifiedEntity { address, .. } => securified(address),
+            Self::UpdateSecurifiedEntity { address, .. } => securified(address),
+            Self::AddAuthenticationSigningKey {
+                address, key_space, ..
+            } => AbstractDerivationRequests::from_iter([
+                DerivationRequestWithoutFactorInKeySpace::new(
+                    address.network_id(),
+                    address.entity_kind(),
+                    CAP26KeyKind::Rola,
+                    *key_space,
+                ),
+            ]),
+        }
+    }
+
     pub fn factor_sources(&self) -> FactorSources {
         match self {
             Self::OARS { factor_sources } => factor_sources.clone(),
-            Self::MARS { factor_source, .. } => FactorSources::just(factor_source.clone()),
-            Self::PreDeriveInstancesForNewFactorSource { factor_source } => {
+            Self::ManualRecoveryScan { factor_source, .. } => {
+                FactorSources::just(factor_source.clone())
+            }
+            Self::PreDeriveInstancesForNewFactorSource { factor_source, .. } => {
+                FactorSources::just(factor_source.clone())
+            }
+            Self::NewVirtualUnsecurifiedEntity { factor_source, .. } => {
                 FactorSources::just(factor_source.clone())
             }
-            Self::NewVirtualUnsecurifiedAccount { factor_source, .. } => {
+            Self::AddAuthenticationSigningKey { factor_source, .. } => {
                 FactorSources::just(factor_source.clone())
             }
-            Self::SecurifyUnsecurifiedAccount {
+            Self::SecurifyUnsecurifiedEntity {
                 matrix_of_factor_sources,
                 ..
             } => matrix_of_factor_sources.all_factor_sources(),
-            Self::UpdateSecurifiedAccount {
+            Self::UpdateSecurifiedEntity {
+                matrix_of_factor_instances,
                 matrix_of_factor_sources,
+                reuse_policy,
                 ..
-            } => matrix_of_factor_sources.all_factor_sources(),
+            } => match reuse_policy {
+                FactorInstanceReusePolicy::ReuseExisting => matrix_of_factor_sources
+                    .all_factor_sources()
+                    .iter()
+                    .filter(|f| {
+                        matrix_of_factor_instances
+                            .instance_of(&f.factor_source_id)
+                            .is_none()
+                    })
+                    .cloned()
+                    .collect(),
+                FactorInstanceReusePolicy::AlwaysDeriveNew => {
+                    matrix_of_factor_sources.all_factor_sources()
+                }
+            },
         }
     }
 }
 
-/// Offsets to next derivation entity index to use for a given
-/// factor source selector.
-#[derive(Default, Clone, Debug, PartialEq, Eq)]
-struct FactorInstancesCacheCursors {
-    map: IndexMap<FactorSourceIDFromHash, HDPathValue>,
-}
-
 pub struct PolyDerivation {
     request_kind: PolyDeriveRequestKind,
 
@@ -84,6 +213,19 @@ pub struct PolyDerivation {
     /// GUI hooks
     derivation_interactors: Arc<dyn DerivationInteractors>,
     is_derivation_done_query: Arc<dyn IsDerivationDoneQuery>,
+
+    /// How factor sources are driven, one at a time or all at once.
+    keys_collector_mode: KeysCollectorMode,
+
+    /// Instances derived so far, and how many indices were scanned.
+    progress: RwLock<DerivationProgress>,
+
+    /// Factor sources which derived any instances, their `last_used_on`
+    /// should be updated.
+    used_factor_sources: RwLock<IndexSet<FactorSourceIDFromHash>>,
+
+    /// Receives progress events, e.g. to be displayed in the UI.
+    progress_listener: Arc<dyn DerivationProgressListener>,
 }
 
 impl PolyDerivation {
@@ -111,9 +253,26 @@ impl PolyDerivation {
             profile_analyser: maybe_profile_analyser.unwrap_or_else(ProfileAnalyzer::dummy),
             derivation_interactors,
             is_derivation_done_query,
+            keys_collector_mode: KeysCollectorMode::default(),
+            progress: RwLock::new(DerivationProgress::default()),
+            used_factor_sources: RwLock::new(IndexSet::new()),
+            progress_listener: Arc::new(NoProgressListener),
         }
     }
 
+    pub fn with_progress_listener(
+        mut self,
+        progress_listener: Arc<dyn DerivationProgressListener>,
+    ) -> Self {
+        self.progress_listener = progress_listener;
+        self
+    }
+
+    pub fn with_keys_collector_mode(mut self, keys_collector_mode: KeysCollectorMode) -> Self {
+        self.keys_collector_mode = keys_collector_mode;
+        self
+    }
+
     pub fn oars(
         factor_sources: &FactorSources,
         gateway: Arc<dyn Gateway>,
@@ -132,7 +291,7 @@ impl PolyDerivation {
         )
     }
 
-    pub fn mars(
+    pub fn manual_recovery_scan<E: Entity>(
         factor_source: &FactorSource,
         gateway: Arc<dyn Gateway>,
         cache: impl Into<Option<Arc<Cache>>>,
@@ -141,7 +300,8 @@ impl PolyDerivation {
         is_derivation_done_query: Arc<dyn IsDerivationDoneQuery>,
     ) -> Self {
         Self::new(
-            PolyDeriveRequestKind::MARS {
+            PolyDeriveRequestKind::ManualRecoveryScan {
+                entity_kind: E::entity_kind(),
                 factor_source: factor_source.clone(),
                 network_id: profile.current_network(),
             },
@@ -153,6 +313,24 @@ impl PolyDerivation {
         )
     }
 
+    pub fn mars(
+        factor_source: &FactorSource,
+        gateway: Arc<dyn Gateway>,
+        cache: impl Into<Option<Arc<Cache>>>,
+        profile: Arc<Profile>,
+        derivation_interactors: Arc<dyn DerivationInteractors>,
+        is_derivation_done_query: Arc<dyn IsDerivationDoneQuery>,
+    ) -> Self {
+        Self::manual_recovery_scan::<Account>(
+            factor_source,
+            gateway,
+            cache,
+            profile,
+            derivation_interactors,
+            is_derivation_done_query,
+        )
+    }
+
     pub fn pre_derive_instance_for_new_factor_source(
         factor_source: &FactorSource,
         gateway: impl Into<Option<Arc<dyn Gateway>>>,
@@ -163,6 +341,7 @@ impl PolyDerivation {
         Self::new(
             PolyDeriveRequestKind::PreDeriveInstancesForNewFactorSource {
                 factor_source: factor_source.clone(),
+                network_id: profile.current_network(),
             },
             cache,
             OnChainAnalyzer::new(gateway),
@@ -172,7 +351,7 @@ impl PolyDerivation {
         )
     }
 
-    pub fn new_virtual_unsecurified_account(
+    pub fn new_virtual_unsecurified_entity<E: Entity>(
         network_id: NetworkID,
         factor_source: &FactorSource,
         gateway: impl Into<Option<Arc<dyn Gateway>>>,
@@ -181,7 +360,8 @@ impl PolyDerivation {
         derivation_interactors: Arc<dyn DerivationInteractors>,
     ) -> Self {
         Self::new(
-            PolyDeriveRequestKind::NewVirtualUnsecurifiedAccount {
+            PolyDeriveRequestKind::NewVirtualUnsecurifiedEntity {
+                entity_kind: E::entity_kind(),
                 network_id,
                 factor_source: factor_source.clone(),
             },
@@ -193,29 +373,72 @@ impl PolyDerivation {
         )
     }
 
-    /// Securify unsecurified Account
-    pub fn securify_unsecurified_account(
-        account_address: AccountAddress,
+    /// Securify unsecurified Account or Persona
+    pub fn securify_unsecurified_entity<E: Entity>(
+        address: E::Address,
         matrix_of_factor_sources: MatrixOfFactorSources,
         gateway: impl Into<Option<Arc<dyn Gateway>>>,
         cache: impl Into<Option<Arc<Cache>>>,
         profile: Arc<Profile>,
         derivation_interactors: Arc<dyn DerivationInteractors>,
     ) -> Self {
-        let unsecurified_account = profile
-            .get_account(&account_address)
-            .unwrap()
-            .as_unsecurified()
-            .unwrap()
-            .clone();
-        //     unsecurified_account: UnsecurifiedAccount,
-        //     matrix_of_factor_sources: MatrixOfFactorSources,
-        // },
+        Self::new(
+            PolyDeriveRequestKind::SecurifyUnsecurifiedEntity {
+                address: address.into(),
+                matrix_of_factor_sources,
+            },
+            cache,
+            OnChainAnalyzer::new(gateway),
+            ProfileAnalyzer::with_profile(profile),
+            derivation_interactors,
+            Arc::new(YesDone),
+        )
+    }
 
+    /// Add a ROLA key to an Account or Persona
+    pub fn add_authentication_signing_key<E: Entity>(
+        entity: &E,
+        factor_source: &FactorSource,
+        gateway: impl Into<Option<Arc<dyn Gateway>>>,
+        cache: impl Into<Option<Arc<Cache>>>,
+        profile: Arc<Profile>,
+        derivation_interactors: Arc<dyn DerivationInteractors>,
+    ) -> Self {
+        let key_space = if entity.as_securified_entity().is_some() {
+            KeySpace::Securified
+        } else {
+            KeySpace::Unsecurified
+        };
         Self::new(
-            PolyDeriveRequestKind::SecurifyUnsecurifiedAccount {
-                unsecurified_account,
+            PolyDeriveRequestKind::AddAuthenticationSigningKey {
+                address: entity.address().into(),
+                key_space,
+                factor_source: factor_source.clone(),
+            },
+            cache,
+            OnChainAnalyzer::new(gateway),
+            ProfileAnalyzer::with_profile(profile),
+            derivation_interactors,
+            Arc::new(YesDone),
+        )
+    }
+
+    /// Update the matrix of securified Account or Persona
+    pub fn update_securified_entity<E: Entity>(
+        securified_entity: &SecurifiedEntity<E::Address>,
+        matrix_of_factor_sources: MatrixOfFactorSources,
+        reuse_policy: FactorInstanceReusePolicy,
+        gateway: impl Into<Option<Arc<dyn Gateway>>>,
+        cache: impl Into<Option<Arc<Cache>>>,
+        profile: Arc<Profile>,
+        derivation_interactors: Arc<dyn DerivationInteractors>,
+    ) -> Self {
+        Self::new(
+            PolyDeriveRequestKind::UpdateSecurifiedEntity {
+                address: securified_entity.address.clone().into(),
+                matrix_of_factor_instances: securified_entity.matrix.clone(),
                 matrix_of_factor_sources,
+                reuse_policy,
             },
             cache,
             OnChainAnalyzer::new(gateway),
@@ -228,99 +451,271 @@ impl PolyDerivation {
 
 #[async_trait]
 pub trait IsDerivationDoneQuery {
-    async fn is_done(&self, derived_accounts: &DerivedFactorInstances) -> Result<bool>;
+    async fn is_done(&self, progress: &DerivationProgress) -> Result<bool>;
 }
 
 pub struct YesDone;
 #[async_trait]
 impl IsDerivationDoneQuery for YesDone {
-    async fn is_done(&self, derived_accounts: &DerivedFactorInstances) -> Result<bool> {
+    async fn is_done(&self, _progress: &DerivationProgress) -> Result<bool> {
         Ok(true)
     }
 }
 
 impl PolyDerivation {
-    async fn is_done(&self, derived_accounts: &DerivedFactorInstances) -> Result<bool> {
-        self.is_derivation_done_query
-            .is_done(derived_accounts)
-            .await
+    async fn is_done(&self, progress: &DerivationProgress) -> Result<bool> {
+        self.is_derivation_done_query.is_done(progress).await
     }
 
     fn requests(&self) -> AbstractDerivationRequests {
-        todo!()
+        self.request_kind.requests()
     }
 
+    /// The factor sources of the request, except those skipped by the user.
     fn factor_sources(&self) -> FactorSources {
-        self.request_kind.factor_sources()
+        let skipped = &self.progress.read().unwrap().skipped;
+        self.request_kind
+            .factor_sources()
+            .iter()
+            .filter(|f| !skipped.contains(&f.factor_source_id))
+            .cloned()
+            .collect()
+    }
+
+    fn unscanned_factor_sources(&self) -> FactorSources {
+        let skipped = &self.progress.read().unwrap().skipped;
+        self.request_kind
+            .factor_sources()
+            .iter()
+            .filter(|f| skipped.contains(&f.factor_source_id))
+            .cloned()
+            .collect()
+    }
+
+    fn emit(&self, event: DerivationProgressEvent) {
+        self.progress_listener.on_event(event)
+    }
+
+    fn next_index_resolver(&self) -> NextDerivationIndexResolver<'_> {
+        NextDerivationIndexResolver::new(
+            &self.cache,
+            &self.profile_analyser,
+            &self.onchain_analyser,
+        )
+    }
+
+    /// Derives instances at the next indices for `requests`, used ones are
+    /// returned and free ones are put in the cache.
+    async fn derive_instances(
+        &self,
+        requests: &IndexSet<DerivationRequestInKeySpace>,
+    ) -> Result<FactorInstances> {
+        // need to determine indices to derive from Cache, Profile and on-chain
+        let resolved = self.next_index_resolver().resolve(requests)?;
+        for (request, resolved) in resolved.iter() {
+            self.emit(DerivationProgressEvent::NextIndexResolved {
+                request: request.clone(),
+                resolved: resolved.clone(),
+            });
+        }
+        // recovery scans derive a batch per prompt, shared by the requests
+        // of each factor source, pre-deriving a batch per request, others
+        // only the next index
+        let indices_per_request = |request: &DerivationRequestInKeySpace| {
+            let factor_source_id = request.factor_source_id();
+            let batch_size = factor_source_id.factor_source_kind.derivation_batch_size();
+            if self.request_kind.is_pre_derive() {
+                return batch_size;
+            }
+            if !self.request_kind.is_recovery_scan() {
+                return 1;
+            }
+            let requests_of_factor_source = requests
+                .iter()
+                .filter(|r| r.factor_source_id() == factor_source_id)
+                .count();
+            (batch_size / requests_of_factor_source).max(1)
+        };
+        let to_derive =
+            NextDerivationIndexResolver::derivation_paths(&resolved, indices_per_request);
+
+        let keys_collector = KeysCollector::with_mode(
+            self.factor_sources(),
+            to_derive,
+            self.derivation_interactors.clone(),
+            self.keys_collector_mode,
+        )?
+        .with_progress_listener(self.progress_listener.clone());
+        let outcome = keys_collector.derive().await?;
+        self.progress
+            .write()
+            .unwrap()
+            .skipped
+            .extend(outcome.skipped);
+        let derived = outcome.factor_instances;
+        self.used_factor_sources
+            .write()
+            .unwrap()
+            .extend(derived.0.iter().map(|fi| fi.factor_source_id()));
+
+        // only instances not used on-chain are probably free, and cached
+        let used = self.onchain_analyser.analyze(&derived).await?;
+        self.cache.advance_cursors_past(&used)?;
+        if !self.onchain_analyser.is_dummy() {
+            self.emit(DerivationProgressEvent::GatewayLookupsDone {
+                lookups: derived.0.len(),
+                used: used.0.len(),
+            });
+        }
+        self.progress.write().unwrap().record_scan(&derived, &used);
+        let probably_free = derived.0.into_iter().filter(|fi| !used.0.contains(fi));
+        self.cache.insert(FactorInstances::from(probably_free))?;
+        Ok(used)
     }
 
     async fn load_or_derive_instances(&self) -> Result<()> {
         let factor_sources = self.factor_sources();
         let abstract_requests = self.requests();
-        let requests_without_indices = abstract_requests.for_each_factor_sources(factor_sources);
-        let cached = self.cache.load(requests_without_indices).await?;
+        let requests = abstract_requests.for_each_factor_sources(factor_sources);
+        self.progress.write().unwrap().rounds += 1;
 
-        let to_derive = IndexMap::new();
-       
+        if self.request_kind.is_recovery_scan() {
+            let used = self.derive_instances(&requests).await?;
+            let access_controllers = self.onchain_analyser.access_controllers_for(&used).await?;
+            let discovered = {
+                let mut progress = self.progress.write().unwrap();
+                let known = progress.derived_instances.entity_addresses();
+                progress.derived_instances.insert(&used);
+                progress
+                    .derived_instances
+                    .rebuild_matrices(&access_controllers);
+                progress
+                    .derived_instances
+                    .entity_addresses()
+                    .difference(&known)
+                    .cloned()
+                    .collect_vec()
+            };
+            for (entity_kind, addresses) in discovered.iter().into_group_map_by(|a| a.entity_kind())
+            {
+                self.emit(DerivationProgressEvent::EntitiesDiscovered {
+                    entity_kind,
+                    count: addresses.len(),
+                });
+            }
+            return Ok(());
+        }
 
-       if !cached.is_empty() {
-            let remaining = derivation_requests - cached;
+        if self.request_kind.is_pre_derive() {
+            // kept in the cache, for later flows to consume
+            self.derive_instances(&requests).await?;
+            return Ok(());
+        }
 
-            if remaining.is_empty() {
-                /// Could satisfy derivation request from cache
-                return Ok(());
+        let mut cached = self.cache.load(requests.clone()).await?;
+        for request in requests.iter() {
+            let request = request.clone();
+            self.emit(if cached.factor_instances.0.contains_key(&request) {
+                DerivationProgressEvent::CacheHit { request }
             } else {
-                to_derive = remaining
+                DerivationProgressEvent::CacheMiss { request }
+            });
+        }
+        // derive more until every request has a free instance, derived ones
+        // used on-chain are skipped, the user might skip factor sources
+        while !cached.is_satisfying_all_requests {
+            let skipped = self.progress.read().unwrap().skipped.clone();
+            let remaining = requests
+                .iter()
+                .filter(|r| !cached.factor_instances.0.contains_key(*r))
+                .filter(|r| !skipped.contains(&r.factor_source_id()))
+                .cloned()
+                .collect::<IndexSet<_>>();
+            if remaining.is_empty() {
+                break;
             }
-        } else {
-            // no cache... need to determine indices to derive from Profile
-            to_derive = self.profile_analyser.next_derivation_paths_fulfilling(&requests_without_indices);
+            self.derive_instances(&remaining).await?;
+            cached = self.cache.load(requests.clone()).await?;
         }
-     
 
-        /// need to derive more
-        let keys_collector = KeysCollector::new(
-            self.factor_sources(),
-            remaining,
-            self.derivation_interactors,
-        )?;
-
-        todo!()
+        let consumed = FactorInstances::from(self.cache.consume(&requests).into_values());
+        self.used_factor_sources
+            .write()
+            .unwrap()
+            .extend(consumed.0.iter().map(|fi| fi.factor_source_id()));
+        self.progress
+            .write()
+            .unwrap()
+            .derived_instances
+            .insert(&consumed);
+        Ok(())
     }
 
     fn derived_instances(&self) -> DerivedFactorInstances {
-        // self.cache.recovered_accounts()
-        todo!()
+        self.progress.read().unwrap().derived_instances.clone()
     }
 
-    pub async fn poly_derive(self) -> Result<FinalDerivationsFinalAndAnalysis> {
-        loop {
-            let is_done = self.is_done(&&self.derived_instances()).await?;
-            if is_done {
-                break;
-            }
-            self.load_or_derive_instances().await?;
-        }
+    fn progress(&self) -> DerivationProgress {
+        self.progress.read().unwrap().clone()
+    }
 
+    fn final_analysis(self) -> FinalDerivationsFinalAndAnalysis {
         let derived_instances = self.derived_instances();
+        let unscanned_factor_sources = self.unscanned_factor_sources();
+        let used_factor_sources = self.used_factor_sources.read().unwrap().clone();
         let cache = self.cache;
 
-        let analysis = FinalDerivationsFinalAndAnalysis {
+        FinalDerivationsFinalAndAnalysis {
             derived_instances,
             cache,
-        };
+            unscanned_factor_sources,
+            used_factor_sources,
+        }
+    }
 
-        Ok(analysis)
+    /// Like `poly_derive` but stops at the next safe point, in between rounds,
+    /// once `cancellation_token` is cancelled, with what was derived so far.
+    pub async fn poly_derive_cancellable(
+        self,
+        cancellation_token: CancellationToken,
+    ) -> Result<PolyDerivationOutcome> {
+        loop {
+            if cancellation_token.is_cancelled() {
+                return Ok(PolyDerivationOutcome::Cancelled(self.final_analysis()));
+            }
+            self.load_or_derive_instances().await?;
+            if self.factor_sources().is_empty() {
+                break;
+            }
+            let is_done = self.is_done(&self.progress()).await?;
+            if is_done {
+                break;
+            }
+        }
+
+        Ok(PolyDerivationOutcome::Finished(self.final_analysis()))
+    }
+
+    /// Derives at least one round, until `is_derivation_done_query` says we
+    /// are done or all factor sources have been skipped by the user.
+    pub async fn poly_derive(self) -> Result<FinalDerivationsFinalAndAnalysis> {
+        let outcome = self
+            .poly_derive_cancellable(CancellationToken::new())
+            .await?;
+        Ok(outcome
+            .into_finished()
+            .expect("Never cancelled without cancelling the token"))
     }
 }
 
-/// onboarding account recover scan
+/// onboarding account recover scan, also returns the factor sources
+/// skipped by the user, which were not scanned.
 pub async fn oars(
     factor_sources: FactorSources,
     interactors: Arc<dyn DerivationInteractors>,
     gateway: Arc<dyn Gateway>,
     is_derivation_done_query: Arc<dyn IsDerivationDoneQuery>,
-) -> Result<(Profile, Arc<Cache>)> {
+) -> Result<(Profile, Arc<Cache>, FactorSources)> {
     let network_id = NetworkID::Mainnet;
 
     let derivation = PolyDerivation::oars(
@@ -332,17 +727,19 @@ pub async fn oars(
 
     let analysis = derivation.poly_derive().await?;
     let cache = analysis.cache;
+    let unscanned_factor_sources = analysis.unscanned_factor_sources;
 
-    let recovered_unsecurified_accounts =
-        analysis.derived_instances.accounts_unsecurified(network_id);
+    let recovered_accounts = analysis.derived_instances.accounts(network_id);
 
-    // TODO handle securified!
-    let profile = Profile::new(factor_sources, recovered_unsecurified_accounts);
+    let mut profile = Profile::new(factor_sources, recovered_accounts);
+    profile.mark_factor_sources_used(&analysis.used_factor_sources);
 
-    Ok((profile, cache))
+    Ok((profile, cache, unscanned_factor_sources))
 }
 
-pub async fn mars(
+/// Manual recovery scan of entities of kind `E`, adds recovered entities to
+/// `profile`.
+pub async fn manual_recovery_scan<E: Entity>(
     factor_source: FactorSource,
     interactors: Arc<dyn DerivationInteractors>,
     gateway: Arc<dyn Gateway>,
@@ -351,7 +748,7 @@ pub async fn mars(
     is_derivation_done_query: Arc<dyn IsDerivationDoneQuery>,
 ) -> Result<Arc<Cache>> {
     let network_id = profile.current_network();
-    let derivation = PolyDerivation::mars(
+    let derivation = PolyDerivation::manual_recovery_scan::<E>(
         &factor_source,
         gateway,
         cache,
@@ -362,13 +759,33 @@ pub async fn mars(
 
     let analysis = derivation.poly_derive().await?;
     let cache = analysis.cache;
-    let accounts = analysis.derived_instances.accounts_unsecurified(network_id);
+    let entities = analysis.derived_instances.entities::<E>(network_id);
 
-    profile.insert_accounts(accounts)?;
+    profile.insert_recovered_entities(entities)?;
+    profile.mark_factor_sources_used(&analysis.used_factor_sources);
 
     Ok(cache)
 }
 
+pub async fn mars(
+    factor_source: FactorSource,
+    interactors: Arc<dyn DerivationInteractors>,
+    gateway: Arc<dyn Gateway>,
+    profile: &mut Profile,
+    cache: impl Into<Option<Arc<Cache>>>,
+    is_derivation_done_query: Arc<dyn IsDerivationDoneQuery>,
+) -> Result<Arc<Cache>> {
+    manual_recovery_scan::<Account>(
+        factor_source,
+        interactors,
+        gateway,
+        profile,
+        cache,
+        is_derivation_done_query,
+    )
+    .await
+}
+
 pub async fn pre_derive_instance_for_new_factor_source(
     factor_source: &FactorSource, // not yet added to Profile.
     gateway: impl Into<Option<Arc<dyn Gateway>>>,
@@ -376,7 +793,6 @@ pub async fn pre_derive_instance_for_new_factor_source(
     profile: &mut Profile,
     derivation_interactors: Arc<dyn DerivationInteractors>,
 ) -> Result<Arc<Cache>> {
-    let network_id = profile.current_network();
     let derivation = PolyDerivation::pre_derive_instance_for_new_factor_source(
         factor_source,
         gateway,
@@ -386,12 +802,58 @@ pub async fn pre_derive_instance_for_new_factor_source(
     );
 
     let analysis = derivation.poly_derive().await?;
+    if !analysis.unscanned_factor_sources.is_empty() {
+        return Err("Factor source skipped by the user, nothing pre-derived".to_owned());
+    }
     let cache = analysis.cache;
     profile.add_factor_source(factor_source.clone())?;
+    profile.mark_factor_sources_used(&analysis.used_factor_sources);
 
     Ok(cache)
 }
 
+pub async fn new_virtual_unsecurified_entity<E: Entity>(
+    name: impl AsRef<str>,
+    network_id: NetworkID,
+    factor_source: &FactorSource,
+    gateway: impl Into<Option<Arc<dyn Gateway>>>,
+    cache: impl Into<Option<Arc<Cache>>>,
+    profile: &mut Profile,
+    derivation_interactors: Arc<dyn DerivationInteractors>,
+) -> Result<E> {
+    let derivation = PolyDerivation::new_virtual_unsecurified_entity::<E>(
+        network_id,
+        factor_source,
+        gateway,
+        cache,
+        Arc::new(profile.clone()),
+        derivation_interactors,
+    );
+
+    let analysis = derivation.poly_derive().await?;
+    if !analysis.unscanned_factor_sources.is_empty() {
+        return Err(format!(
+            "Factor source skipped by the user, no {:?} created",
+            E::entity_kind()
+        ));
+    }
+
+    let mut entity = analysis
+        .derived_instances
+        .unsecurified_entities::<E>(network_id)
+        .first()
+        .cloned()
+        .map(E::from_unsecurified)
+        .ok_or_else(|| format!("No {:?}", E::entity_kind()))?;
+
+    entity.set_name(name);
+
+    profile.insert_entities(IndexSet::from_iter([entity.clone()]))?;
+    profile.mark_factor_sources_used(&analysis.used_factor_sources);
+
+    Ok(entity)
+}
+
 pub async fn new_virtual_unsecurified_account(
     name: impl AsRef<str>,
     network_id: NetworkID,
@@ -401,28 +863,1105 @@ pub async fn new_virtual_unsecurified_account(
     profile: &mut Profile,
     derivation_interactors: Arc<dyn DerivationInteractors>,
 ) -> Result<Account> {
-    let network_id = profile.current_network();
-    let derivation = PolyDerivation::new_virtual_unsecurified_account(
+    new_virtual_unsecurified_entity(
+        name,
         network_id,
         factor_source,
         gateway,
         cache,
+        profile,
+        derivation_interactors,
+    )
+    .await
+}
+
+/// Like `new_virtual_unsecurified_account` but with the factor source picked
+/// by `Profile::default_factor_source` using `policy`.
+pub async fn new_virtual_unsecurified_account_with_default_factor_source(
+    name: impl AsRef<str>,
+    network_id: NetworkID,
+    policy: &FactorSourceSelectionPolicy,
+    gateway: impl Into<Option<Arc<dyn Gateway>>>,
+    cache: impl Into<Option<Arc<Cache>>>,
+    profile: &mut Profile,
+    derivation_interactors: Arc<dyn DerivationInteractors>,
+) -> Result<Account> {
+    let factor_source = profile.default_factor_source(policy)?;
+    new_virtual_unsecurified_account(
+        name,
+        network_id,
+        &factor_source,
+        gateway,
+        cache,
+        profile,
+        derivation_interactors,
+    )
+    .await
+}
+
+/// Securifies the unsecurified entity at `address` with
+/// `matrix_of_factor_sources`, using one instance in securified key space
+/// per factor source, and replaces it in `profile`.
+pub async fn securify_unsecurified_entity<E: Entity>(
+    address: E::Address,
+    matrix_of_factor_sources: MatrixOfFactorSources,
+    gateway: impl Into<Option<Arc<dyn Gateway>>>,
+    cache: impl Into<Option<Arc<Cache>>>,
+    profile: &mut Profile,
+    derivation_interactors: Arc<dyn DerivationInteractors>,
+) -> Result<SecurifiedEntity<E::Address>> {
+    let unsecurified_entity = profile
+        .get_entity::<E>(&address)?
+        .as_unsecurified_entity()
+        .cloned()
+        .ok_or_else(|| format!("{:?} is already securified", E::entity_kind()))?;
+    matrix_of_factor_sources.validate()?;
+
+    let derivation = PolyDerivation::securify_unsecurified_entity::<E>(
+        address,
+        matrix_of_factor_sources.clone(),
+        gateway,
+        cache,
         Arc::new(profile.clone()),
         derivation_interactors,
     );
 
     let analysis = derivation.poly_derive().await?;
+    if !analysis.unscanned_factor_sources.is_empty() {
+        return Err("All factor sources of the matrix are required".to_owned());
+    }
+
+    let matrix = matrix_of_factor_sources
+        .with_instances(&analysis.derived_instances.securified_factor_instances())?;
+    let securified_entity = SecurifiedEntity::securifying(unsecurified_entity, matrix);
+
+    profile.update_entity(E::from_securified(securified_entity.clone()))?;
+    profile.mark_factor_sources_used(&analysis.used_factor_sources);
+
+    Ok(securified_entity)
+}
+
+pub async fn securify_unsecurified_account(
+    account_address: AccountAddress,
+    matrix_of_factor_sources: MatrixOfFactorSources,
+    gateway: impl Into<Option<Arc<dyn Gateway>>>,
+    cache: impl Into<Option<Arc<Cache>>>,
+    profile: &mut Profile,
+    derivation_interactors: Arc<dyn DerivationInteractors>,
+) -> Result<SecurifiedAccount> {
+    securify_unsecurified_entity::<Account>(
+        account_address,
+        matrix_of_factor_sources,
+        gateway,
+        cache,
+        profile,
+        derivation_interactors,
+    )
+    .await
+}
 
-    let mut account = analysis
+/// Derives a ROLA key with `factor_source` for the entity at `address`, in
+/// securified key space if it is securified, else in unsecurified, and sets
+/// it as the authentication signing key of the entity in `profile`,
+/// replacing any existing one.
+pub async fn add_authentication_signing_key<E: Entity>(
+    address: E::Address,
+    factor_source: &FactorSource,
+    gateway: impl Into<Option<Arc<dyn Gateway>>>,
+    cache: impl Into<Option<Arc<Cache>>>,
+    profile: &mut Profile,
+    derivation_interactors: Arc<dyn DerivationInteractors>,
+) -> Result<E> {
+    let kind = factor_source.factor_source_id.factor_source_kind;
+    if !kind.capabilities().allowed_for_rola {
+        return Err(format!("{:?} cannot be used for ROLA keys", kind));
+    }
+    let mut entity = profile.get_entity::<E>(&address)?;
+
+    let derivation = PolyDerivation::add_authentication_signing_key(
+        &entity,
+        factor_source,
+        gateway,
+        cache,
+        Arc::new(profile.clone()),
+        derivation_interactors,
+    );
+
+    let analysis = derivation.poly_derive().await?;
+    if !analysis.unscanned_factor_sources.is_empty() {
+        return Err(
+            "Factor source skipped by the user, no authentication signing key derived".to_owned(),
+        );
+    }
+    let key = analysis
         .derived_instances
-        .accounts_unsecurified(network_id)
-        .first()
-        .ok_or_else(|| "No account".to_owned())
-        .cloned()?;
+        .all_factor_instances()
+        .0
+        .into_iter()
+        .find(|fi| fi.derivation_path().key_kind == CAP26KeyKind::Rola)
+        .ok_or_else(|| "No authentication signing key derived".to_owned())?;
+
+    entity.set_authentication_signing_key(key);
+    profile.update_entity(entity.clone())?;
+    profile.mark_factor_sources_used(&analysis.used_factor_sources);
+
+    Ok(entity)
+}
+
+/// The new matrix of a securified entity, to be applied on-chain, and how
+/// it differs from the current one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpdatedMatrixOfFactorInstances {
+    pub matrix: MatrixOfFactorInstances,
+    pub diff: MatrixOfFactorInstancesDiff,
+}
+
+/// Builds a new matrix for the securified entity at `address` from
+/// `matrix_of_factor_sources`, only deriving instances for factor sources
+/// not already in its matrix, unless `reuse_policy` says otherwise.
+/// The entity in `profile` is not updated, since the matrix must first be
+/// changed on-chain, only `last_used_on` of the factor sources used.
+pub async fn update_securified_entity<E: Entity>(
+    address: E::Address,
+    matrix_of_factor_sources: MatrixOfFactorSources,
+    reuse_policy: FactorInstanceReusePolicy,
+    gateway: impl Into<Option<Arc<dyn Gateway>>>,
+    cache: impl Into<Option<Arc<Cache>>>,
+    profile: &mut Profile,
+    derivation_interactors: Arc<dyn DerivationInteractors>,
+) -> Result<UpdatedMatrixOfFactorInstances> {
+    let securified_entity = profile
+        .get_entity::<E>(&address)?
+        .as_securified_entity()
+        .cloned()
+        .ok_or_else(|| format!("{:?} is not securified", E::entity_kind()))?;
+    matrix_of_factor_sources.validate()?;
+
+    let derivation = PolyDerivation::update_securified_entity::<E>(
+        &securified_entity,
+        matrix_of_factor_sources.clone(),
+        reuse_policy,
+        gateway,
+        cache,
+        Arc::new(profile.clone()),
+        derivation_interactors,
+    );
+    let to_derive = derivation.request_kind.factor_sources();
+
+    let mut instances = IndexSet::new();
+    if !to_derive.is_empty() {
+        let analysis = derivation.poly_derive().await?;
+        if !analysis.unscanned_factor_sources.is_empty() {
+            return Err("All new factor sources of the matrix are required".to_owned());
+        }
+        instances.extend(analysis.derived_instances.securified_factor_instances());
+        profile.mark_factor_sources_used(&analysis.used_factor_sources);
+    }
+    instances.extend(
+        matrix_of_factor_sources
+            .all_factor_sources()
+            .iter()
+            .filter(|f| !to_derive.contains(&f.factor_source_id))
+            .filter_map(|f| securified_entity.matrix.instance_of(&f.factor_source_id)),
+    );
+
+    let matrix = matrix_of_factor_sources.with_instances(&instances)?;
+    let diff = securified_entity.diff(&matrix);
+    Ok(UpdatedMatrixOfFactorInstances { matrix, diff })
+}
+
+pub async fn update_securified_account(
+    account_address: AccountAddress,
+    matrix_of_factor_sources: MatrixOfFactorSources,
+    reuse_policy: FactorInstanceReusePolicy,
+    gateway: impl Into<Option<Arc<dyn Gateway>>>,
+    cache: impl Into<Option<Arc<Cache>>>,
+    profile: &mut Profile,
+    derivation_interactors: Arc<dyn DerivationInteractors>,
+) -> Result<UpdatedMatrixOfFactorInstances> {
+    update_securified_entity::<Account>(
+        account_address,
+        matrix_of_factor_sources,
+        reuse_policy,
+        gateway,
+        cache,
+        profile,
+        derivation_interactors,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poly_derive::test_helpers::*;
+
+    #[tokio::test]
+    async fn new_account_skips_indices_used_on_chain() {
+        let device = factor_source();
+        let used = [0, 1].map(|n| account_instance(&device, KeySpace::Unsecurified, n));
+        let gateway = InMemoryGateway::new(InMemoryGatewayFixture {
+            used_key_hashes: used.iter().cloned().map(PublicKeyHash::new).collect(),
+            ..Default::default()
+        });
+        let mut profile = Profile::new(FactorSources::just(device.clone()), IndexSet::new());
+
+        let account = new_virtual_unsecurified_account(
+            "Alice",
+            NetworkID::Mainnet,
+            &device,
+            Arc::new(gateway) as Arc<dyn Gateway>,
+            None,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            account.all_factor_instances(),
+            IndexSet::<_>::from_iter([account_instance(&device, KeySpace::Unsecurified, 2)])
+        );
+    }
+
+    struct FailingInteractor;
+
+    #[async_trait]
+    impl DerivationInteractors for FailingInteractor {
+        async fn derive(
+            &self,
+            _request: FactorSourceDerivationRequest,
+        ) -> FactorSourceDerivationOutcome {
+            FactorSourceDerivationOutcome::Failed("Should use cache".to_owned())
+        }
+    }
+
+    #[tokio::test]
+    async fn oars_finishes_with_partial_results_if_factor_source_skipped() {
+        let device = factor_source();
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let device_veci = account_instance(&device, KeySpace::Unsecurified, 0);
+        let gateway = InMemoryGateway::new(InMemoryGatewayFixture {
+            used_key_hashes: IndexSet::from_iter([
+                PublicKeyHash::new(device_veci.clone()),
+                PublicKeyHash::new(account_instance(&ledger, KeySpace::Unsecurified, 0)),
+            ]),
+            ..Default::default()
+        });
+
+        let (profile, _, unscanned) = oars(
+            FactorSources::from_iter([device.clone(), ledger.clone()]),
+            Arc::new(SkipsKind(FactorSourceKind::Ledger)),
+            Arc::new(gateway),
+            Arc::new(YesDone),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(unscanned, FactorSources::just(ledger));
+        assert_eq!(
+            profile.network(NetworkID::Mainnet).accounts,
+            IndexSet::<_>::from_iter([Account::new_unsecurified(
+                FactorInstanceInUnsecurifiedSpace::new(device_veci),
+                NetworkID::Mainnet
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn new_account_fails_if_factor_source_skipped() {
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let mut profile = Profile::new(FactorSources::just(ledger.clone()), IndexSet::new());
+
+        let result = new_virtual_unsecurified_account(
+            "Alice",
+            NetworkID::Mainnet,
+            &ledger,
+            None,
+            None,
+            &mut profile,
+            Arc::new(SkipsKind(FactorSourceKind::Ledger)),
+        )
+        .await;
+
+        assert_eq!(
+            result,
+            Err("Factor source skipped by the user, no Account created".to_owned())
+        );
+        assert!(profile.network(NetworkID::Mainnet).accounts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pre_derive_fills_cache_with_a_batch_per_request() {
+        let device = factor_source();
+        let mut profile = Profile::default();
+
+        let cache = pre_derive_instance_for_new_factor_source(
+            &device,
+            None,
+            None,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        let cached = cache.snapshot().factor_instances.0;
+        let requests = [CAP26EntityKind::Account, CAP26EntityKind::Identity]
+            .into_iter()
+            .cartesian_product([CAP26KeyKind::T9n, CAP26KeyKind::Rola])
+            .cartesian_product([KeySpace::Unsecurified, KeySpace::Securified])
+            .map(|((entity_kind, key_kind), key_space)| {
+                DerivationRequestInKeySpace::new(
+                    device.factor_source_id.clone(),
+                    NetworkID::Mainnet,
+                    entity_kind,
+                    key_kind,
+                    key_space,
+                )
+            })
+            .collect::<IndexSet<_>>();
+        assert_eq!(cached.keys().cloned().collect::<IndexSet<_>>(), requests);
+        for (request, instances) in cached {
+            let first = CAP26Index::first_in(request.key_space);
+            assert_eq!(
+                instances,
+                FactorInstances::from((0..20).map(|n| instance_at(
+                    request.derivation_path_at(std::iter::Step::forward(first.clone(), n))
+                )))
+            );
+        }
+
+        // consumed by later flows, without deriving
+        let account = new_virtual_unsecurified_account(
+            "Alice",
+            NetworkID::Mainnet,
+            &device,
+            None,
+            cache.clone(),
+            &mut profile,
+            Arc::new(FailingInteractor),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            account.all_factor_instances(),
+            IndexSet::<_>::from_iter([account_instance(&device, KeySpace::Unsecurified, 0)])
+        );
+    }
+
+    #[tokio::test]
+    async fn pre_derive_skips_rola_keys_if_not_allowed_for_factor_source_kind() {
+        let off_device = factor_source_with(2, FactorSourceKind::OffDeviceMnemonic);
+
+        let cache = pre_derive_instance_for_new_factor_source(
+            &off_device,
+            None,
+            None,
+            &mut Profile::default(),
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        let cached = cache.snapshot().factor_instances.0;
+        assert_eq!(cached.len(), 4);
+        assert!(cached
+            .keys()
+            .all(|request| request.key_kind == CAP26KeyKind::T9n));
+    }
+
+    #[tokio::test]
+    async fn cancelled_between_rounds_with_partial_instances_and_consistent_cache() {
+        let device = factor_source();
+        // a Device derives indices 0..10 in the first round, 10..20 in the second
+        let used = account_instance(&device, KeySpace::Unsecurified, 11);
+        let gateway = InMemoryGateway::new(InMemoryGatewayFixture {
+            used_key_hashes: IndexSet::from_iter([PublicKeyHash::new(used.clone())]),
+            ..Default::default()
+        });
+        let token = CancellationToken::new();
+        let token_ = token.clone();
+        let rounds = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        // cancelled during the second round, honoured once it is done
+        let outcome = PolyDerivation::oars(
+            &FactorSources::just(device.clone()),
+            Arc::new(gateway),
+            Arc::new(TestDerivationInteractor),
+            Arc::new(GapLimit::default()),
+        )
+        .with_progress_listener(Arc::new(move |event| {
+            if let DerivationProgressEvent::BatchStarted { .. } = event {
+                if rounds.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 1 {
+                    token_.cancel();
+                }
+            }
+        }))
+        .poly_derive_cancellable(token)
+        .await
+        .unwrap();
+
+        let analysis = outcome.into_cancelled().unwrap();
+        assert_eq!(
+            analysis.derived_instances.unsecurified_factor_instances(),
+            IndexSet::<_>::from_iter([FactorInstanceInUnsecurifiedSpace::new(used)])
+        );
+        let instances_at = |key_space, indices: Vec<usize>| {
+            FactorInstances::from(
+                indices
+                    .into_iter()
+                    .map(|n| account_instance(&device, key_space, n)),
+            )
+        };
+        let unsecurified = account_request(&device, KeySpace::Unsecurified);
+        let securified = account_request(&device, KeySpace::Securified);
+        assert_eq!(
+            analysis.cache.snapshot(),
+            CacheSnapshot {
+                factor_instances: CachedFactorInstances(IndexMap::from_iter([
+                    (
+                        unsecurified.clone(),
+                        instances_at(
+                            KeySpace::Unsecurified,
+                            (0..20).filter(|n| *n != 11).collect()
+                        )
+                    ),
+                    (
+                        securified.clone(),
+                        instances_at(KeySpace::Securified, (0..20).collect())
+                    ),
+                ])),
+                cursors: FactorInstancesCacheCursors(IndexMap::from_iter([
+                    (
+                        unsecurified,
+                        account_instance(&device, KeySpace::Unsecurified, 20)
+                            .derivation_path()
+                            .index()
+                    ),
+                    (
+                        securified,
+                        account_instance(&device, KeySpace::Securified, 20)
+                            .derivation_path()
+                            .index()
+                    ),
+                ])),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn recovery_scan_emits_events_counting_entities() {
+        let device = factor_source();
+        let veci = account_instance(&device, KeySpace::Unsecurified, 0);
+        let matrix_key = account_instance(&device, KeySpace::Securified, 0);
+        let unsecurified = account_instance(&device, KeySpace::Unsecurified, 12);
+        let gateway = InMemoryGateway::new(InMemoryGatewayFixture {
+            used_key_hashes: IndexSet::from_iter([
+                PublicKeyHash::new(veci.clone()),
+                PublicKeyHash::new(unsecurified),
+            ]),
+            access_controllers: IndexSet::from_iter([OnChainAccessController {
+                entity_address: AccountAddress::new(veci, NetworkID::Mainnet).into(),
+                threshold: 1,
+                threshold_key_hashes: vec![PublicKeyHash::new(matrix_key)],
+                override_key_hashes: vec![],
+            }]),
+        });
+        let (listener, mut events) = DerivationProgressChannel::new();
+
+        PolyDerivation::oars(
+            &FactorSources::just(device.clone()),
+            Arc::new(gateway),
+            Arc::new(TestDerivationInteractor),
+            Arc::new(GapLimit::new(15)),
+        )
+        .with_progress_listener(listener)
+        .poly_derive()
+        .await
+        .unwrap();
+
+        let mut discovered = Vec::new();
+        let mut batches = 0;
+        let mut resolved = Vec::new();
+        while let Some(event) = events.recv().await {
+            match event {
+                DerivationProgressEvent::EntitiesDiscovered { entity_kind, count } => {
+                    discovered.push((entity_kind, count))
+                }
+                DerivationProgressEvent::BatchStarted { .. } => batches += 1,
+                DerivationProgressEvent::NextIndexResolved { resolved: r, .. } => {
+                    resolved.push(r.decided_by)
+                }
+                _ => {}
+            }
+        }
+        // a Device derives 10 indices per key space per round, round 1 finds
+        // the securified account, with two used keys, round 2 the
+        // unsecurified one, round 3 nothing
+        assert_eq!(
+            discovered,
+            vec![(CAP26EntityKind::Account, 1), (CAP26EntityKind::Account, 1)]
+        );
+        assert_eq!(batches, 3);
+        assert_eq!(
+            resolved[..2],
+            [NextIndexSource::Default, NextIndexSource::Default]
+        );
+        assert!(resolved[2..]
+            .iter()
+            .all(|source| *source == NextIndexSource::Cache));
+    }
+
+    #[tokio::test]
+    async fn oars_recovers_securified_account_with_partial_matrix() {
+        let device = factor_source();
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let absent = factor_source_with(3, FactorSourceKind::Ledger);
+
+        let veci = account_instance(&device, KeySpace::Unsecurified, 0);
+        let device_key = account_instance(&device, KeySpace::Securified, 0);
+        let ledger_key = account_instance(&ledger, KeySpace::Securified, 1);
+        let absent_key = account_instance(&absent, KeySpace::Securified, 0);
+        let unsecurified = account_instance(&device, KeySpace::Unsecurified, 1);
+
+        let address = AccountAddress::new(veci.clone(), NetworkID::Mainnet);
+        let gateway = InMemoryGateway::new(InMemoryGatewayFixture {
+            used_key_hashes: IndexSet::from_iter([
+                PublicKeyHash::new(veci.clone()),
+                PublicKeyHash::new(unsecurified.clone()),
+            ]),
+            access_controllers: IndexSet::from_iter([OnChainAccessController {
+                entity_address: address.clone().into(),
+                threshold: 2,
+                threshold_key_hashes: vec![
+                    PublicKeyHash::new(device_key.clone()),
+                    PublicKeyHash::new(absent_key.clone()),
+                ],
+                override_key_hashes: vec![PublicKeyHash::new(ledger_key.clone())],
+            }]),
+        });
+
+        let (mut profile, cache, unscanned) = oars(
+            FactorSources::from_iter([device.clone(), ledger.clone()]),
+            Arc::new(TestDerivationInteractor),
+            Arc::new(gateway),
+            Arc::new(GapLimit::new(3)),
+        )
+        .await
+        .unwrap();
+
+        assert!(unscanned.is_empty());
+        assert_eq!(profile.network(NetworkID::Mainnet).accounts.len(), 2);
+        assert_eq!(
+            profile.get_account(&AccountAddress::new(unsecurified, NetworkID::Mainnet)),
+            Ok(Account::new_unsecurified(
+                FactorInstanceInUnsecurifiedSpace::new(account_instance(
+                    &device,
+                    KeySpace::Unsecurified,
+                    1
+                )),
+                NetworkID::Mainnet
+            ))
+        );
+        let securified = profile
+            .get_account(&address)
+            .unwrap()
+            .into_securified()
+            .unwrap();
+        assert_eq!(securified.veci, Some(veci));
+        let device_key = FactorInstanceInSecurifiedSpace::new(device_key);
+        let ledger_key = FactorInstanceInSecurifiedSpace::new(ledger_key);
+        assert_eq!(
+            securified.matrix,
+            MatrixOfFactorInstances::new(vec![device_key.clone()], 2, vec![ledger_key.clone()])
+        );
+        assert_eq!(
+            securified.missing_key_hashes,
+            MatrixOfPublicKeyHashes::new(vec![PublicKeyHash::new(absent_key.clone())], 2, vec![])
+        );
+
+        // The key of the absent factor source is removed by an update, even
+        // though its instance was never derived.
+        let updated = update_securified_account(
+            address,
+            MatrixOfFactorSources::new(vec![device], 1, vec![ledger]),
+            FactorInstanceReusePolicy::ReuseExisting,
+            None,
+            cache,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            updated.diff,
+            MatrixOfFactorInstancesDiff {
+                added: IndexSet::new(),
+                removed: IndexSet::new(),
+                kept: IndexSet::from_iter([device_key, ledger_key]),
+                removed_missing_key_hashes: IndexSet::from_iter([PublicKeyHash::new(absent_key)]),
+                old_threshold: 2,
+                new_threshold: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn securify_replaces_unsecurified_account() {
+        let device = factor_source();
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let arculus = factor_source_with(3, FactorSourceKind::ArculusCard);
+        let mut profile = Profile::new(
+            FactorSources::from_iter([device.clone(), ledger.clone(), arculus.clone()]),
+            IndexSet::new(),
+        );
+        let cache = Arc::new(Cache::default());
+        let account = new_virtual_unsecurified_account(
+            "Alice",
+            NetworkID::Mainnet,
+            &device,
+            None,
+            cache.clone(),
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+        let unsecurified = account.as_unsecurified().unwrap().clone();
+
+        let securified = securify_unsecurified_account(
+            account.address(),
+            MatrixOfFactorSources::new(
+                vec![device.clone(), ledger.clone()],
+                1,
+                vec![arculus.clone()],
+            ),
+            None,
+            cache,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        let key = |f: &FactorSource| {
+            FactorInstanceInSecurifiedSpace::new(account_instance(f, KeySpace::Securified, 0))
+        };
+        assert_eq!(securified.address, unsecurified.address);
+        assert_eq!(securified.veci, Some(unsecurified.veci));
+        assert_eq!(
+            securified.matrix,
+            MatrixOfFactorInstances::new(vec![key(&device), key(&ledger)], 1, vec![key(&arculus)])
+        );
+        assert_eq!(profile.network(NetworkID::Mainnet).accounts.len(), 1);
+        assert_eq!(
+            profile.get_account(&account.address()),
+            Ok(Account::Securified(securified))
+        );
+    }
+
+    async fn securified_profile(
+        matrix: MatrixOfFactorSources,
+    ) -> (Profile, Arc<Cache>, SecurifiedAccount) {
+        let device = factor_source();
+        let mut profile = Profile::new(matrix.all_factor_sources(), IndexSet::new());
+        let cache = Arc::new(Cache::default());
+        let account = new_virtual_unsecurified_account(
+            "Alice",
+            NetworkID::Mainnet,
+            &device,
+            None,
+            cache.clone(),
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+        let securified = securify_unsecurified_account(
+            account.address(),
+            matrix,
+            None,
+            cache.clone(),
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+        (profile, cache, securified)
+    }
+
+    #[tokio::test]
+    async fn update_securified_account_derives_only_for_new_factor_sources() {
+        let device = factor_source();
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let arculus = factor_source_with(3, FactorSourceKind::ArculusCard);
+        let (mut profile, cache, securified) = securified_profile(MatrixOfFactorSources::new(
+            vec![device.clone(), ledger.clone()],
+            2,
+            vec![],
+        ))
+        .await;
+
+        let updated = update_securified_account(
+            securified.address.clone(),
+            MatrixOfFactorSources::new(vec![device.clone(), arculus.clone()], 1, vec![]),
+            FactorInstanceReusePolicy::ReuseExisting,
+            None,
+            cache,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        let key = |f: &FactorSource, n| {
+            FactorInstanceInSecurifiedSpace::new(account_instance(f, KeySpace::Securified, n))
+        };
+        assert_eq!(
+            updated.matrix,
+            MatrixOfFactorInstances::new(vec![key(&device, 0), key(&arculus, 0)], 1, vec![])
+        );
+        assert_eq!(
+            updated.diff,
+            MatrixOfFactorInstancesDiff {
+                added: IndexSet::from_iter([key(&arculus, 0)]),
+                removed: IndexSet::from_iter([key(&ledger, 0)]),
+                kept: IndexSet::from_iter([key(&device, 0)]),
+                removed_missing_key_hashes: IndexSet::new(),
+                old_threshold: 2,
+                new_threshold: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn update_securified_account_derives_new_for_all_if_policy_says_so() {
+        let device = factor_source();
+        let (mut profile, cache, securified) =
+            securified_profile(MatrixOfFactorSources::new(vec![device.clone()], 1, vec![])).await;
+
+        let updated = update_securified_account(
+            securified.address.clone(),
+            MatrixOfFactorSources::new(vec![device.clone()], 1, vec![]),
+            FactorInstanceReusePolicy::AlwaysDeriveNew,
+            None,
+            cache,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        let key = |n| {
+            FactorInstanceInSecurifiedSpace::new(account_instance(&device, KeySpace::Securified, n))
+        };
+        assert_eq!(
+            updated.matrix,
+            MatrixOfFactorInstances::new(vec![key(1)], 1, vec![])
+        );
+        assert_eq!(updated.diff.removed, IndexSet::<_>::from_iter([key(0)]));
+        assert!(updated.diff.kept.is_empty());
+    }
+
+    #[tokio::test]
+    async fn persona_is_created_and_securified_next_to_account() {
+        let device = factor_source();
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let mut profile = Profile::new(
+            FactorSources::from_iter([device.clone(), ledger.clone()]),
+            IndexSet::new(),
+        );
+        let cache = Arc::new(Cache::default());
+        let account = new_virtual_unsecurified_account(
+            "Alice",
+            NetworkID::Mainnet,
+            &device,
+            None,
+            cache.clone(),
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+        let persona = new_virtual_unsecurified_entity::<Persona>(
+            "Satoshi",
+            NetworkID::Mainnet,
+            &device,
+            None,
+            cache.clone(),
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        let veci = persona.as_unsecurified().unwrap().veci.clone();
+        assert_eq!(
+            veci.derivation_path().entity_kind,
+            CAP26EntityKind::Identity
+        );
+        assert_ne!(account.all_factor_instances().first(), Some(&veci));
+        assert!(persona.address().to_string().starts_with("identity_rdx1"));
+        assert!(account.address().to_string().starts_with("account_rdx1"));
+
+        let securified = securify_unsecurified_entity::<Persona>(
+            persona.address(),
+            MatrixOfFactorSources::new(vec![device.clone(), ledger.clone()], 2, vec![]),
+            None,
+            cache,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(securified.veci, Some(veci));
+        assert!(securified
+            .matrix
+            .all_factor_instances()
+            .iter()
+            .all(|fi| fi.derivation_path().entity_kind == CAP26EntityKind::Identity));
+        assert_eq!(profile.network(NetworkID::Mainnet).accounts.len(), 1);
+        assert_eq!(
+            profile.network(NetworkID::Mainnet).personas,
+            IndexSet::<_>::from_iter([Persona::Securified(securified)])
+        );
+    }
+
+    #[tokio::test]
+    async fn authentication_signing_key_in_key_space_of_entity() {
+        let device = factor_source();
+        let (mut profile, cache, securified) =
+            securified_profile(MatrixOfFactorSources::new(vec![device.clone()], 1, vec![])).await;
+        let persona = new_virtual_unsecurified_entity::<Persona>(
+            "Satoshi",
+            NetworkID::Mainnet,
+            &device,
+            None,
+            cache.clone(),
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        let account = add_authentication_signing_key::<Account>(
+            securified.address.clone(),
+            &device,
+            None,
+            cache.clone(),
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+        let persona = add_authentication_signing_key::<Persona>(
+            persona.address(),
+            &device,
+            None,
+            cache,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
 
-    account.set_name(name);
+        let account_key = account.authentication_signing_key().unwrap();
+        assert_eq!(account_key.key_space(), KeySpace::Securified);
+        assert_eq!(account_key.derivation_path().key_kind, CAP26KeyKind::Rola);
+        let persona_key = persona.authentication_signing_key().unwrap();
+        assert_eq!(persona_key.key_space(), KeySpace::Unsecurified);
+        assert_eq!(
+            persona_key.derivation_path().entity_kind,
+            CAP26EntityKind::Identity
+        );
+        assert_eq!(profile.get_account(&securified.address), Ok(account));
+        assert_eq!(profile.get_persona(&persona.address()), Ok(persona));
+        assert_eq!(profile.network(NetworkID::Mainnet).personas.len(), 1);
+    }
 
-    profile.insert_accounts(IndexSet::from_iter([account.clone()]))?;
+    #[tokio::test]
+    async fn new_account_respects_requested_network() {
+        let device = factor_source();
+        let mut profile = Profile::new(FactorSources::just(device.clone()), IndexSet::new());
+        let cache = Arc::new(Cache::default());
+        let mut accounts = Vec::new();
+        for network_id in [NetworkID::Mainnet, NetworkID::Stokenet] {
+            let account = new_virtual_unsecurified_account(
+                "Alice",
+                network_id,
+                &device,
+                None,
+                cache.clone(),
+                &mut profile,
+                Arc::new(TestDerivationInteractor),
+            )
+            .await
+            .unwrap();
+            accounts.push(account);
+        }
+        let (mainnet, stokenet) = (accounts[0].clone(), accounts[1].clone());
 
-    Ok(account)
+        assert_eq!(mainnet.address().network_id, NetworkID::Mainnet);
+        assert_eq!(stokenet.address().network_id, NetworkID::Stokenet);
+        assert_eq!(
+            stokenet.all_factor_instances()[0]
+                .derivation_path()
+                .network_id,
+            NetworkID::Stokenet
+        );
+        assert_eq!(
+            profile.network(NetworkID::Mainnet).accounts,
+            IndexSet::<_>::from_iter([mainnet])
+        );
+        assert_eq!(
+            profile.network(NetworkID::Stokenet).accounts,
+            IndexSet::<_>::from_iter([stokenet])
+        );
+        assert_eq!(profile.current_network(), NetworkID::Mainnet);
+        profile.switch_network(NetworkID::Stokenet);
+        assert_eq!(profile.current_network(), NetworkID::Stokenet);
+    }
+
+    #[tokio::test]
+    async fn recovery_scan_derives_a_batch_per_prompt() {
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let prompts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let prompts_ = prompts.clone();
+
+        let analysis = PolyDerivation::oars(
+            &FactorSources::just(ledger.clone()),
+            Arc::new(InMemoryGateway::new(InMemoryGatewayFixture::default())),
+            Arc::new(TestDerivationInteractor),
+            Arc::new(GapLimit::new(20)),
+        )
+        .with_progress_listener(Arc::new(move |event| {
+            if let DerivationProgressEvent::BatchStarted {
+                derivation_paths, ..
+            } = event
+            {
+                prompts_.lock().unwrap().push(derivation_paths.len());
+            }
+        }))
+        .poly_derive()
+        .await
+        .unwrap();
+
+        // a batch of 10 per prompt, 5 indices of each key space, instead of
+        // a prompt per index
+        assert_eq!(*prompts.lock().unwrap(), vec![10; 4]);
+        assert_eq!(
+            analysis
+                .cache
+                .cursors()
+                .next_index(&account_request(&ledger, KeySpace::Securified)),
+            Some(
+                account_instance(&ledger, KeySpace::Securified, 20)
+                    .derivation_path()
+                    .index()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn derivation_updates_last_used_on_of_factor_source() {
+        let never_used = |f: FactorSource| {
+            f.with_metadata(FactorSourceMetadata {
+                last_used_on: std::time::UNIX_EPOCH,
+                ..FactorSourceMetadata::labelled("never used")
+            })
+        };
+        let device = never_used(factor_source());
+        let ledger = never_used(factor_source_with(2, FactorSourceKind::Ledger));
+        let mut profile = Profile::new(
+            FactorSources::from_iter([device.clone(), ledger.clone()]),
+            IndexSet::new(),
+        );
+
+        new_virtual_unsecurified_account(
+            "Alice",
+            NetworkID::Mainnet,
+            &ledger,
+            None,
+            None,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        let sorted = profile.factor_sources.sorted_by_last_used();
+        assert_eq!(
+            sorted.iter().map(|f| &f.factor_source_id).collect_vec(),
+            vec![&ledger.factor_source_id, &device.factor_source_id]
+        );
+        assert!(sorted[0].last_used_on() > std::time::UNIX_EPOCH);
+        assert_eq!(sorted[1].last_used_on(), std::time::UNIX_EPOCH);
+        assert_eq!(sorted[0].metadata.label, "never used");
+    }
+
+    #[tokio::test]
+    async fn consuming_cached_instances_updates_last_used_on_of_factor_source() {
+        let ledger =
+            factor_source_with(2, FactorSourceKind::Ledger).with_metadata(FactorSourceMetadata {
+                last_used_on: std::time::UNIX_EPOCH,
+                ..FactorSourceMetadata::new()
+            });
+        let mut profile = Profile::new(FactorSources::just(ledger.clone()), IndexSet::new());
+        let cache = Arc::new(Cache::default());
+        cache
+            .insert(FactorInstances::from([account_instance(
+                &ledger,
+                KeySpace::Unsecurified,
+                0,
+            )]))
+            .unwrap();
+
+        new_virtual_unsecurified_account(
+            "Alice",
+            NetworkID::Mainnet,
+            &ledger,
+            None,
+            cache,
+            &mut profile,
+            Arc::new(FailingInteractor),
+        )
+        .await
+        .unwrap();
+
+        let used = profile.factor_sources.sorted_by_last_used();
+        assert_eq!(used[0].factor_source_id, ledger.factor_source_id);
+        assert!(used[0].last_used_on() > std::time::UNIX_EPOCH);
+    }
+
+    #[tokio::test]
+    async fn new_account_uses_main_device_factor_source_by_default() {
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let main = factor_source().with_metadata(FactorSourceMetadata {
+            flags: std::collections::BTreeSet::from_iter([FactorSourceFlag::Main]),
+            ..FactorSourceMetadata::labelled("Phone")
+        });
+        let mut profile = Profile::new(
+            FactorSources::from_iter([ledger, main.clone()]),
+            IndexSet::new(),
+        );
+
+        let account = new_virtual_unsecurified_account_with_default_factor_source(
+            "Alice",
+            NetworkID::Mainnet,
+            &FactorSourceSelectionPolicy::default(),
+            None,
+            None,
+            &mut profile,
+            Arc::new(TestDerivationInteractor),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            account.all_factor_instances()[0].factor_source_id(),
+            main.factor_source_id
+        );
+    }
 }
@@ -1,29 +1,156 @@
+use std::{collections::BTreeSet, time::SystemTime};
+
 use enum_as_inner::EnumAsInner;
 
 use crate::prelude::*;
 
 pub type Result<T, E = String> = std::result::Result<T, E>;
 
+/// A factor source is identified by its `factor_source_id`, compare those
+/// to find the same factor source with e.g. a different `last_used_on`.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct FactorSource {
     pub factor_source_id: FactorSourceIDFromHash,
+    pub metadata: FactorSourceMetadata,
+}
+impl FactorSource {
+    /// A new factor source with empty label, added and last used now.
+    pub fn new(factor_source_id: FactorSourceIDFromHash) -> Self {
+        Self {
+            factor_source_id,
+            metadata: FactorSourceMetadata::new(),
+        }
+    }
+    pub fn with_metadata(mut self, metadata: FactorSourceMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+    pub fn factor_source_kind(&self) -> FactorSourceKind {
+        self.factor_source_id.factor_source_kind
+    }
+    pub fn last_used_on(&self) -> SystemTime {
+        self.metadata.last_used_on
+    }
+    pub fn is_main_bdfs(&self) -> bool {
+        self.metadata.flags.contains(&FactorSourceFlag::Main)
+    }
+    pub fn is_deleted_by_user(&self) -> bool {
+        self.metadata
+            .flags
+            .contains(&FactorSourceFlag::DeletedByUser)
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FactorSourceFlag {
+    /// The main "Babylon Device Factor Source", used by default to create
+    /// new entities.
+    Main,
+    /// The user deleted the factor source, it is kept since entities might
+    /// still reference it.
+    DeletedByUser,
+}
+
+/// User facing information about a factor source, shown in the UI.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FactorSourceMetadata {
+    pub label: String,
+    /// E.g. "iPhone 15 Pro" for Device, "Nano S+" for Ledger.
+    pub device_model: Option<String>,
+    /// E.g. "My Phone", the name the user gave the device.
+    pub device_name: Option<String>,
+    pub added_on: SystemTime,
+    /// Updated every time the factor source is used to derive.
+    pub last_used_on: SystemTime,
+    pub flags: BTreeSet<FactorSourceFlag>,
+}
+impl FactorSourceMetadata {
+    pub fn new() -> Self {
+        Self::labelled("")
+    }
+    pub fn labelled(label: impl AsRef<str>) -> Self {
+        let now = SystemTime::now();
+        Self {
+            label: label.as_ref().to_owned(),
+            device_model: None,
+            device_name: None,
+            added_on: now,
+            last_used_on: now,
+            flags: BTreeSet::new(),
+        }
+    }
+}
+impl Default for FactorSourceMetadata {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum FactorSourceKind {
     Device,
     Ledger,
+    ArculusCard,
+    OffDeviceMnemonic,
+    SecurityQuestions,
+    TrustedContact,
+    Password,
 }
+
+/// What a kind of factor source can be used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FactorSourceKindCapabilities {
+    /// If the factor source can derive without prompting the user.
+    pub derives_silently: bool,
+    /// Number of keys derived in one go, `None` if it does not support
+    /// batching, i.e. derives a single key at a time. Recovery scans derive
+    /// this many keys per round, pre-deriving this many per request.
+    pub derivation_batch_size: Option<usize>,
+    pub allowed_in_threshold_role: bool,
+    pub allowed_in_override_role: bool,
+    /// If it can be used for ROLA keys, i.e. authentication signing.
+    pub allowed_for_rola: bool,
+}
+
 impl FactorSourceKind {
-    pub fn derivation_batch_size(&self) -> usize {
-        match self {
-            Self::Device => 20,
-            Self::Ledger => 10,
+    pub fn capabilities(&self) -> FactorSourceKindCapabilities {
+        let (
+            derives_silently,
+            derivation_batch_size,
+            allowed_in_threshold_role,
+            allowed_in_override_role,
+            allowed_for_rola,
+        ) = match self {
+            Self::Device => (true, Some(20), true, true, true),
+            Self::Ledger => (false, Some(10), true, true, true),
+            Self::ArculusCard => (false, Some(5), true, true, true),
+            Self::OffDeviceMnemonic => (false, Some(20), true, true, false),
+            Self::SecurityQuestions => (false, Some(20), true, false, false),
+            Self::TrustedContact => (false, None, false, true, false),
+            Self::Password => (false, Some(20), true, false, false),
+        };
+        FactorSourceKindCapabilities {
+            derives_silently,
+            derivation_batch_size,
+            allowed_in_threshold_role,
+            allowed_in_override_role,
+            allowed_for_rola,
         }
     }
+
+    /// If the factor source can derive without prompting the user.
+    pub fn derives_silently(&self) -> bool {
+        self.capabilities().derives_silently
+    }
+
+    /// Number of keys derived in one go, 1 if it does not support
+    /// batching.
+    pub fn derivation_batch_size(&self) -> usize {
+        self.capabilities().derivation_batch_size.unwrap_or(1)
+    }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PublicKey {
     pub bytes: [u8; 32],
 }
@@ -33,49 +160,202 @@ pub struct PublicKeyHash {
     bytes: [u8; 29],
 }
 impl PublicKeyHash {
-    fn hashing(public_key: PublicKey) -> Self {
+    pub(crate) fn hashing(public_key: PublicKey) -> Self {
         use sha256::digest;
         let digest_hex = digest(&public_key.bytes);
         let digest = hex::decode(digest_hex).unwrap();
+        // last 29 bytes of the hash, like Radix does
         Self {
-            bytes: digest.as_slice().try_into().unwrap(),
+            bytes: digest[digest.len() - 29..].try_into().unwrap(),
         }
     }
     pub fn new(factor_instance: impl Into<FactorInstance>) -> Self {
         let factor_instance = factor_instance.into();
         Self::hashing(factor_instance.public_key())
     }
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.bytes)
+    }
+    pub fn from_hex(hex: impl AsRef<str>) -> Result<Self> {
+        let bytes = hex::decode(hex.as_ref()).map_err(|e| e.to_string())?;
+        let bytes = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| format!("Expected 29 bytes, got: {}", bytes.len()))?;
+        Ok(Self { bytes })
+    }
+}
+impl Serialize for PublicKeyHash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+impl<'de> Deserialize<'de> for PublicKeyHash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Self::from_hex(hex).map_err(serde::de::Error::custom)
+    }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct AccountAddress {
     pub network_id: NetworkID,
     pub public_key_hash: PublicKeyHash,
 }
 impl AccountAddress {
+    pub const HRP: &'static str = "account_";
+
     pub fn new(factor_instance: impl Into<FactorInstance>, network_id: NetworkID) -> Self {
         let factor_instance = factor_instance.into();
         Self {
             network_id,
-            public_key_hash: factor_instance.factor_source_id.public_key_hash,
+            public_key_hash: PublicKeyHash::new(factor_instance),
         }
     }
 }
+impl std::fmt::Display for AccountAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}1{}",
+            Self::HRP,
+            self.network_id.hrp_suffix(),
+            self.public_key_hash.to_hex()
+        )
+    }
+}
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IdentityAddress {
+    pub network_id: NetworkID,
+    pub public_key_hash: PublicKeyHash,
+}
+impl IdentityAddress {
+    pub const HRP: &'static str = "identity_";
+
+    pub fn new(factor_instance: impl Into<FactorInstance>, network_id: NetworkID) -> Self {
+        let factor_instance = factor_instance.into();
+        Self {
+            network_id,
+            public_key_hash: PublicKeyHash::new(factor_instance),
+        }
+    }
+}
+impl std::fmt::Display for IdentityAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}1{}",
+            Self::HRP,
+            self.network_id.hrp_suffix(),
+            self.public_key_hash.to_hex()
+        )
+    }
+}
+
+/// Address of an entity which can be securified by an AccessController.
+///
+/// Serialized as a single key map, `{ "Account": {..} }` or
+/// `{ "Identity": {..} }`, in YAML too, i.e. `Account: {..}` rather than
+/// a `!Account` tag.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, EnumAsInner)]
+#[serde(
+    into = "AddressOfAccountOrPersonaMap",
+    try_from = "AddressOfAccountOrPersonaMap"
+)]
+pub enum AddressOfAccountOrPersona {
+    Account(AccountAddress),
+    Identity(IdentityAddress),
+}
+
+/// Serde form of `AddressOfAccountOrPersona`, a map with exactly one key.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddressOfAccountOrPersonaMap {
+    #[serde(rename = "Account", default, skip_serializing_if = "Option::is_none")]
+    account: Option<AccountAddress>,
+    #[serde(rename = "Identity", default, skip_serializing_if = "Option::is_none")]
+    identity: Option<IdentityAddress>,
+}
+impl From<AddressOfAccountOrPersona> for AddressOfAccountOrPersonaMap {
+    fn from(value: AddressOfAccountOrPersona) -> Self {
+        match value {
+            AddressOfAccountOrPersona::Account(a) => Self {
+                account: Some(a),
+                identity: None,
+            },
+            AddressOfAccountOrPersona::Identity(a) => Self {
+                account: None,
+                identity: Some(a),
+            },
+        }
+    }
+}
+impl TryFrom<AddressOfAccountOrPersonaMap> for AddressOfAccountOrPersona {
+    type Error = String;
+    fn try_from(value: AddressOfAccountOrPersonaMap) -> Result<Self> {
+        match (value.account, value.identity) {
+            (Some(a), None) => Ok(Self::Account(a)),
+            (None, Some(a)) => Ok(Self::Identity(a)),
+            _ => Err("Expected exactly one of `Account` or `Identity`".to_owned()),
+        }
+    }
+}
+impl From<AccountAddress> for AddressOfAccountOrPersona {
+    fn from(value: AccountAddress) -> Self {
+        Self::Account(value)
+    }
+}
+impl From<IdentityAddress> for AddressOfAccountOrPersona {
+    fn from(value: IdentityAddress) -> Self {
+        Self::Identity(value)
+    }
+}
+impl AddressOfAccountOrPersona {
+    pub fn entity_kind(&self) -> CAP26EntityKind {
+        match self {
+            Self::Account(_) => CAP26EntityKind::Account,
+            Self::Identity(_) => CAP26EntityKind::Identity,
+        }
+    }
+
+    pub fn network_id(&self) -> NetworkID {
+        match self {
+            Self::Account(a) => a.network_id,
+            Self::Identity(a) => a.network_id,
+        }
+    }
+}
+impl std::fmt::Display for AddressOfAccountOrPersona {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Account(a) => a.fmt(f),
+            Self::Identity(a) => a.fmt(f),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct FactorSourceIDFromHash {
     /// Hash at special node.
     pub public_key_hash: PublicKeyHash,
     pub factor_source_kind: FactorSourceKind,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct FactorInstance {
     derivation_path: DerivationPath,
     public_key: PublicKey,
     factor_source_id: FactorSourceIDFromHash,
 }
 impl FactorInstance {
+    pub fn new(derivation_path: DerivationPath, public_key: PublicKey) -> Self {
+        Self {
+            factor_source_id: derivation_path.factor_source_id(),
+            derivation_path,
+            public_key,
+        }
+    }
     pub fn public_key(&self) -> PublicKey {
         self.public_key.clone()
     }
@@ -99,6 +379,46 @@ pub struct MatrixOfAbstractFactor<T> {
     threshold: usize,
     override_factors: Vec<T>,
 }
+impl<T> MatrixOfAbstractFactor<T> {
+    pub fn new(threshold_factors: Vec<T>, threshold: usize, override_factors: Vec<T>) -> Self {
+        Self {
+            threshold_factors,
+            threshold,
+            override_factors,
+        }
+    }
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+    pub fn threshold_factors(&self) -> &[T] {
+        &self.threshold_factors
+    }
+    pub fn override_factors(&self) -> &[T] {
+        &self.override_factors
+    }
+
+    /// Maps every factor of both roles, keeping roles and threshold.
+    pub fn try_map<U>(
+        &self,
+        mut transform: impl FnMut(&T) -> Result<U>,
+    ) -> Result<MatrixOfAbstractFactor<U>> {
+        let threshold_factors = self
+            .threshold_factors
+            .iter()
+            .map(&mut transform)
+            .collect::<Result<Vec<_>>>()?;
+        let override_factors = self
+            .override_factors
+            .iter()
+            .map(&mut transform)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(MatrixOfAbstractFactor::new(
+            threshold_factors,
+            self.threshold,
+            override_factors,
+        ))
+    }
+}
 pub type MatrixOfFactorSources = MatrixOfAbstractFactor<FactorSource>;
 impl MatrixOfFactorSources {
     pub fn all_factor_sources(&self) -> FactorSources {
@@ -107,85 +427,950 @@ impl MatrixOfFactorSources {
         set.extend(self.override_factors.iter().cloned());
         FactorSources::from_iter(set)
     }
+
+    /// Checks that the matrix can sign, i.e. that it has any factor and
+    /// that `threshold` is reachable, and that every factor source is
+    /// allowed in its role.
+    pub fn validate(&self) -> Result<()> {
+        if self.threshold_factors.is_empty() && self.override_factors.is_empty() {
+            return Err("Matrix has no factor sources".to_owned());
+        }
+        if self.threshold > self.threshold_factors.len() {
+            return Err(format!(
+                "Threshold {} exceeds the {} threshold factors",
+                self.threshold,
+                self.threshold_factors.len()
+            ));
+        }
+        let check = |factors: &[FactorSource],
+                     role: &str,
+                     is_allowed: fn(&FactorSourceKindCapabilities) -> bool| {
+            factors
+                .iter()
+                .map(|f| f.factor_source_id.factor_source_kind)
+                .find(|kind| !is_allowed(&kind.capabilities()))
+                .map_or(Ok(()), |kind| {
+                    Err(format!("{:?} is not allowed in the {} role", kind, role))
+                })
+        };
+        check(&self.threshold_factors, "threshold", |c| {
+            c.allowed_in_threshold_role
+        })?;
+        check(&self.override_factors, "override", |c| {
+            c.allowed_in_override_role
+        })
+    }
+
+    /// The matrix of instances with the instance of each factor source
+    /// from `instances`, which must contain one for every factor source.
+    pub fn with_instances(
+        &self,
+        instances: &IndexSet<FactorInstanceInSecurifiedSpace>,
+    ) -> Result<MatrixOfFactorInstances> {
+        self.try_map(|factor_source| {
+            instances
+                .iter()
+                .find(|fi| fi.instance().factor_source_id() == factor_source.factor_source_id)
+                .cloned()
+                .ok_or_else(|| {
+                    format!(
+                        "No instance derived for factor source {:?}",
+                        factor_source.factor_source_id
+                    )
+                })
+        })
+    }
 }
 pub type MatrixOfFactorInstances = MatrixOfAbstractFactor<FactorInstanceInSecurifiedSpace>;
+impl MatrixOfFactorInstances {
+    pub fn all_factor_instances(&self) -> IndexSet<FactorInstance> {
+        self.threshold_factors
+            .iter()
+            .chain(self.override_factors.iter())
+            .map(|f| f.instance())
+            .collect()
+    }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    /// The instance of the factor source with `factor_source_id`, if any.
+    pub fn instance_of(
+        &self,
+        factor_source_id: &FactorSourceIDFromHash,
+    ) -> Option<FactorInstanceInSecurifiedSpace> {
+        self.threshold_factors
+            .iter()
+            .chain(self.override_factors.iter())
+            .find(|f| f.instance().factor_source_id() == *factor_source_id)
+            .cloned()
+    }
+
+    /// How `new` differs from this matrix.
+    pub fn diff(&self, new: &Self) -> MatrixOfFactorInstancesDiff {
+        let old_instances = self.all_securified_instances();
+        let new_instances = new.all_securified_instances();
+        MatrixOfFactorInstancesDiff {
+            added: new_instances.difference(&old_instances).cloned().collect(),
+            removed: old_instances.difference(&new_instances).cloned().collect(),
+            kept: old_instances
+                .intersection(&new_instances)
+                .cloned()
+                .collect(),
+            removed_missing_key_hashes: IndexSet::new(),
+            old_threshold: self.threshold,
+            new_threshold: new.threshold,
+        }
+    }
+
+    fn all_securified_instances(&self) -> IndexSet<FactorInstanceInSecurifiedSpace> {
+        self.threshold_factors
+            .iter()
+            .chain(self.override_factors.iter())
+            .cloned()
+            .collect()
+    }
+}
+
+/// Hashes of public keys, by role, e.g. the owner keys of an
+/// AccessController whose instances are not known.
+pub type MatrixOfPublicKeyHashes = MatrixOfAbstractFactor<PublicKeyHash>;
+impl MatrixOfPublicKeyHashes {
+    pub fn all_key_hashes(&self) -> IndexSet<PublicKeyHash> {
+        self.threshold_factors
+            .iter()
+            .chain(self.override_factors.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// If there are no key hashes in either role.
+    pub fn is_empty(&self) -> bool {
+        self.threshold_factors.is_empty() && self.override_factors.is_empty()
+    }
+}
+
+/// The matrix of an AccessController on-chain rebuilt from derived
+/// instances. Owner keys without a derived instance, e.g. of factor
+/// sources not at hand during recovery, are kept in `missing` so that
+/// a partial matrix is not mistaken for the complete one.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RecoveredMatrixOfFactorInstances {
+    pub matrix: MatrixOfFactorInstances,
+    pub missing: MatrixOfPublicKeyHashes,
+}
+impl RecoveredMatrixOfFactorInstances {
+    /// Rebuilds the matrix of `access_controller` from the `instances` whose
+    /// public key hashes are in its roles, keeping threshold and roles.
+    pub fn from_access_controller(
+        access_controller: &OnChainAccessController,
+        instances: &FactorInstances,
+    ) -> Self {
+        let instances_by_hash = instances
+            .0
+            .iter()
+            .filter(|fi| fi.key_space() == KeySpace::Securified)
+            .map(|fi| (PublicKeyHash::new(fi.clone()), fi.clone()))
+            .collect::<IndexMap<_, _>>();
+        let role = |hashes: &[PublicKeyHash]| {
+            let (found, missing): (Vec<_>, Vec<_>) = hashes
+                .iter()
+                .partition(|h| instances_by_hash.contains_key(*h));
+            (
+                found
+                    .into_iter()
+                    .map(|h| FactorInstanceInSecurifiedSpace::new(instances_by_hash[h].clone()))
+                    .collect_vec(),
+                missing.into_iter().cloned().collect_vec(),
+            )
+        };
+        let (threshold_factors, missing_threshold_factors) =
+            role(&access_controller.threshold_key_hashes);
+        let (override_factors, missing_override_factors) =
+            role(&access_controller.override_key_hashes);
+        Self {
+            matrix: MatrixOfFactorInstances::new(
+                threshold_factors,
+                access_controller.threshold,
+                override_factors,
+            ),
+            missing: MatrixOfPublicKeyHashes::new(
+                missing_threshold_factors,
+                access_controller.threshold,
+                missing_override_factors,
+            ),
+        }
+    }
+
+    /// If every owner key has a derived instance.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Instances added, removed and kept when changing the matrix of a
+/// securified account, regardless of role.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatrixOfFactorInstancesDiff {
+    pub added: IndexSet<FactorInstanceInSecurifiedSpace>,
+    pub removed: IndexSet<FactorInstanceInSecurifiedSpace>,
+    pub kept: IndexSet<FactorInstanceInSecurifiedSpace>,
+    /// Owner keys of the old matrix whose instances were never derived,
+    /// always removed since the new matrix is built from instances.
+    pub removed_missing_key_hashes: IndexSet<PublicKeyHash>,
+    pub old_threshold: usize,
+    pub new_threshold: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[repr(u8)]
 pub enum NetworkID {
-    Mainnet,
-    Testnet,
+    Mainnet = 0x01,
+    Stokenet = 0x02,
+    Adapanet = 0x0a,
+    Nebunet = 0x0b,
+    Kisharnet = 0x0c,
+    Ansharnet = 0x0d,
+    Zabanet = 0x0e,
+    Gilganet = 0x20,
+    Enkinet = 0x21,
+    Hammunet = 0x22,
+    Nergalnet = 0x23,
+    Mardunet = 0x24,
+    Dumunet = 0x25,
+    Localnet = 0xf0,
+    InternalTestnet = 0xf1,
+    Simulator = 0xf2,
 }
+impl NetworkID {
+    pub const ALL: [Self; 16] = [
+        Self::Mainnet,
+        Self::Stokenet,
+        Self::Adapanet,
+        Self::Nebunet,
+        Self::Kisharnet,
+        Self::Ansharnet,
+        Self::Zabanet,
+        Self::Gilganet,
+        Self::Enkinet,
+        Self::Hammunet,
+        Self::Nergalnet,
+        Self::Mardunet,
+        Self::Dumunet,
+        Self::Localnet,
+        Self::InternalTestnet,
+        Self::Simulator,
+    ];
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    /// The canonical numeric ID of this network.
+    pub fn discriminant(&self) -> u8 {
+        *self as u8
+    }
+
+    /// Suffix of the Bech32 HRP of addresses on this network.
+    pub fn hrp_suffix(&self) -> &'static str {
+        match self {
+            Self::Mainnet => "rdx",
+            Self::Stokenet => "tdx_2_",
+            Self::Adapanet => "tdx_a_",
+            Self::Nebunet => "tdx_b_",
+            Self::Kisharnet => "tdx_c_",
+            Self::Ansharnet => "tdx_d_",
+            Self::Zabanet => "tdx_e_",
+            Self::Gilganet => "tdx_20_",
+            Self::Enkinet => "tdx_21_",
+            Self::Hammunet => "tdx_22_",
+            Self::Nergalnet => "tdx_23_",
+            Self::Mardunet => "tdx_24_",
+            Self::Dumunet => "tdx_25_",
+            Self::Localnet => "loc",
+            Self::InternalTestnet => "test",
+            Self::Simulator => "sim",
+        }
+    }
+
+    pub fn from_hrp_suffix(hrp_suffix: impl AsRef<str>) -> Result<Self> {
+        let hrp_suffix = hrp_suffix.as_ref();
+        Self::ALL
+            .into_iter()
+            .find(|n| n.hrp_suffix() == hrp_suffix)
+            .ok_or_else(|| format!("Unknown network HRP suffix: {}", hrp_suffix))
+    }
+
+    /// The network component of CAP26 derivation paths, unhardened.
+    pub fn cap26_value(&self) -> u32 {
+        self.discriminant() as u32
+    }
+
+    pub fn from_cap26_value(value: u32) -> Result<Self> {
+        u8::try_from(value)
+            .map_err(|_| format!("Unknown network ID: {}", value))
+            .and_then(Self::try_from)
+    }
+}
+impl From<NetworkID> for u8 {
+    fn from(value: NetworkID) -> Self {
+        value.discriminant()
+    }
+}
+impl TryFrom<u8> for NetworkID {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|n| n.discriminant() == value)
+            .ok_or_else(|| format!("Unknown network ID: {}", value))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum CAP26KeyKind {
     T9n,
     Rola,
 }
+impl CAP26KeyKind {
+    /// The key kind component of CAP-26 derivation paths.
+    pub fn cap26_value(&self) -> u32 {
+        match self {
+            Self::T9n => 1460,
+            Self::Rola => 1678,
+        }
+    }
+}
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum CAP26EntityKind {
     Account,
     Identity,
 }
-
-#[derive(Clone, Debug, PartialEq, Eq, Hash, EnumAsInner)]
-pub enum Account {
-    Unsecurified(UnsecurifiedAccount),
-    Securified(SecurifiedAccount),
-}
-impl Account {
-    pub fn new_unsecurified(
-        instance: FactorInstanceInUnsecurifiedSpace,
-        network_id: NetworkID,
-    ) -> Self {
-        Self::Unsecurified(UnsecurifiedAccount::new(instance, network_id))
-    }
-    pub fn set_name(&mut self, _name: impl AsRef<str>) {
-        // noop
-    }
-    pub fn address(&self) -> AccountAddress {
+impl CAP26EntityKind {
+    /// The entity kind component of CAP-26 derivation paths.
+    pub fn cap26_value(&self) -> u32 {
         match self {
-            Self::Unsecurified(a) => a.address.clone(),
-            Self::Securified(a) => a.address.clone(),
+            Self::Account => 525,
+            Self::Identity => 618,
         }
     }
 }
 
+/// Accounts and personas of a single network in Profile.
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct ProfileNetwork {
+    pub accounts: IndexSet<Account>,
+    pub personas: IndexSet<Persona>,
+}
+
+/// How `Profile::default_factor_source` picks the factor source used to
+/// create new entities when the user did not pick one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FactorSourceSelectionPolicy {
+    /// Kinds to fall back to, in order, if there is no main Device factor
+    /// source. Among factor sources of the same kind the most recently used
+    /// is picked.
+    pub preference_order: Vec<FactorSourceKind>,
+    /// Only consider factor sources which derive without prompting the user.
+    pub require_silent_derivation: bool,
+}
+impl Default for FactorSourceSelectionPolicy {
+    fn default() -> Self {
+        Self {
+            preference_order: vec![
+                FactorSourceKind::Device,
+                FactorSourceKind::Ledger,
+                FactorSourceKind::ArculusCard,
+                FactorSourceKind::OffDeviceMnemonic,
+            ],
+            require_silent_derivation: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Profile {
     pub factor_sources: FactorSources,
-    pub accounts: IndexSet<Account>,
+    networks: IndexMap<NetworkID, ProfileNetwork>,
+    current_network: NetworkID,
+}
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            factor_sources: FactorSources::default(),
+            networks: IndexMap::new(),
+            current_network: NetworkID::Mainnet,
+        }
+    }
 }
 impl Profile {
+    /// A Profile on `Mainnet` with `accounts`, on the networks of their
+    /// addresses.
     pub fn new(factor_sources: FactorSources, accounts: IndexSet<Account>) -> Self {
-        Self {
+        let mut profile = Self {
             factor_sources,
-            accounts,
-        }
+            ..Self::default()
+        };
+        profile
+            .insert_entities(accounts)
+            .expect("Accounts are unique");
+        profile
+    }
+
+    pub fn with_personas(mut self, personas: IndexSet<Persona>) -> Self {
+        self.insert_entities(personas).expect("Personas are unique");
+        self
     }
 
     pub fn current_network(&self) -> NetworkID {
-        NetworkID::Mainnet
+        self.current_network
     }
 
-    pub fn get_account(&self, address: &AccountAddress) -> Result<Account> {
-        self.accounts
-            .iter()
-            .find(|a| a.address() == *address)
-            .cloned()
-            .ok_or("Account not found".to_owned())
+    /// Switches to `network_id`, which need not have any entities yet.
+    pub fn switch_network(&mut self, network_id: NetworkID) {
+        self.current_network = network_id;
     }
 
-    pub fn insert_accounts(&mut self, accounts: IndexSet<Account>) -> Result<()> {
-        let count = self.accounts.len();
-        let expected_after_insertion = count + accounts.len();
-        self.accounts.extend(accounts);
-        assert_eq!(self.accounts.len(), expected_after_insertion);
+    /// The networks with any entities.
+    pub fn networks(&self) -> impl Iterator<Item = NetworkID> + '_ {
+        self.networks.keys().copied()
+    }
+
+    /// Entities on `network_id`, empty if there are none.
+    pub fn network(&self, network_id: NetworkID) -> ProfileNetwork {
+        self.networks.get(&network_id).cloned().unwrap_or_default()
+    }
+
+    fn network_mut(&mut self, network_id: NetworkID) -> &mut ProfileNetwork {
+        self.networks.entry(network_id).or_default()
+    }
+
+    /// The entity at `address`, on the network of the address.
+    pub fn get_entity<E: Entity>(&self, address: &E::Address) -> Result<E> {
+        self.networks
+            .get(&address.network_id())
+            .and_then(|network| {
+                E::entities_in(network)
+                    .iter()
+                    .find(|e| e.address() == *address)
+                    .cloned()
+            })
+            .ok_or(format!("{:?} not found", E::entity_kind()))
+    }
+
+    /// Inserts `entities`, each on the network of its address, or none of
+    /// them if any address is already in Profile or occurs twice.
+    pub fn insert_entities<E: Entity>(&mut self, entities: IndexSet<E>) -> Result<()> {
+        let mut addresses = IndexSet::new();
+        for entity in &entities {
+            let address = entity.address();
+            if self.get_entity::<E>(&address).is_ok() || !addresses.insert(address) {
+                return Err(format!("{:?} already in Profile", E::entity_kind()));
+            }
+        }
+        for entity in entities {
+            E::entities_in_mut(self.network_mut(entity.address().network_id())).insert(entity);
+        }
         Ok(())
     }
 
-    pub fn add_factor_source(&mut self, factor_source: FactorSource) -> Result<()> {
-        self.factor_sources.insert(factor_source);
+    /// Inserts `entities` found by a recovery scan. Those at new addresses
+    /// are inserted, an unsecurified entity in Profile is replaced by the
+    /// securified entity recovered at its address, keeping its instances,
+    /// any other entity in Profile is kept as is. Changes nothing if an
+    /// address occurs twice in `entities`.
+    pub fn insert_recovered_entities<E: Entity>(&mut self, entities: IndexSet<E>) -> Result<()> {
+        if !entities.iter().map(|e| e.address()).all_unique() {
+            return Err(format!("{:?} recovered twice", E::entity_kind()));
+        }
+        for recovered in entities {
+            let Ok(existing) = self.get_entity::<E>(&recovered.address()) else {
+                self.insert_entities(IndexSet::from_iter([recovered]))?;
+                continue;
+            };
+            let (Some(unsecurified), Some(securified)) = (
+                existing.as_unsecurified_entity(),
+                recovered.as_securified_entity(),
+            ) else {
+                continue;
+            };
+            let mut securified = securified.clone();
+            securified
+                .veci
+                .get_or_insert_with(|| unsecurified.veci.clone());
+            if securified.authentication_signing_key.is_none() {
+                securified.authentication_signing_key =
+                    unsecurified.authentication_signing_key.clone();
+            }
+            self.update_entity(E::from_securified(securified))?;
+        }
         Ok(())
     }
+
+    /// Replaces the entity with the same address as `entity`.
+    pub fn update_entity<E: Entity>(&mut self, entity: E) -> Result<()> {
+        let address = entity.address();
+        let existing = self.get_entity::<E>(&address)?;
+        let entities = E::entities_in_mut(self.network_mut(address.network_id()));
+        *entities = std::mem::take(entities)
+            .into_iter()
+            .map(|e| if e == existing { entity.clone() } else { e })
+            .collect();
+        Ok(())
+    }
+
+    pub fn get_account(&self, address: &AccountAddress) -> Result<Account> {
+        self.get_entity(address)
+    }
+
+    pub fn get_persona(&self, address: &IdentityAddress) -> Result<Persona> {
+        self.get_entity(address)
+    }
+
+    /// All factor instances of all accounts and personas, on all networks.
+    pub fn all_factor_instances(&self) -> IndexSet<FactorInstance> {
+        self.networks
+            .values()
+            .flat_map(|network| {
+                network
+                    .accounts
+                    .iter()
+                    .flat_map(|a| a.all_factor_instances())
+                    .chain(
+                        network
+                            .personas
+                            .iter()
+                            .flat_map(|p| p.all_factor_instances()),
+                    )
+            })
+            .collect()
+    }
+
+    pub fn add_factor_source(&mut self, factor_source: FactorSource) -> Result<()> {
+        self.factor_sources.insert(factor_source)
+    }
+
+    /// Removes the factor source with `id`, failing if it is unknown or any
+    /// entity on any network still references it.
+    pub fn remove_factor_source(&mut self, id: &FactorSourceIDFromHash) -> Result<FactorSource> {
+        if !self.factor_sources.contains(id) {
+            return Err(format!("Unknown factor source: {:?}", id));
+        }
+        if self
+            .all_factor_instances()
+            .iter()
+            .any(|fi| fi.factor_source_id() == *id)
+        {
+            return Err(format!("Factor source is referenced by entities: {:?}", id));
+        }
+        Ok(self.factor_sources.remove(id).expect("checked above"))
+    }
+
+    /// The factor source to create new entities with, on any network since
+    /// factor sources are network-agnostic: the main Device factor source if
+    /// any, else the most recently used one of the first kind in
+    /// `policy.preference_order` present. Factor sources deleted by the user
+    /// are never picked.
+    pub fn default_factor_source(
+        &self,
+        policy: &FactorSourceSelectionPolicy,
+    ) -> Result<FactorSource> {
+        let candidates = self
+            .factor_sources
+            .sorted_by_last_used()
+            .into_iter()
+            .filter(|f| !f.is_deleted_by_user())
+            .collect_vec();
+        if candidates.is_empty() {
+            return Err("No factor source".to_owned());
+        }
+        let candidates = candidates
+            .into_iter()
+            .filter(|f| {
+                !policy.require_silent_derivation || f.factor_source_kind().derives_silently()
+            })
+            .collect_vec();
+        if candidates.is_empty() {
+            return Err("No factor source can derive silently".to_owned());
+        }
+        let main = candidates.iter().find(|f| {
+            f.is_main_bdfs()
+                && f.factor_source_kind() == FactorSourceKind::Device
+                && policy.preference_order.contains(&FactorSourceKind::Device)
+        });
+        main.or_else(|| {
+            policy
+                .preference_order
+                .iter()
+                .find_map(|kind| candidates.iter().find(|f| f.factor_source_kind() == *kind))
+        })
+        .cloned()
+        .ok_or_else(|| format!("No factor source of kind {:?}", policy.preference_order))
+    }
+
+    /// Called after a successful derivation, so that the UI can list
+    /// factor sources by when they were last used.
+    pub fn mark_factor_sources_used(&mut self, ids: &IndexSet<FactorSourceIDFromHash>) {
+        self.factor_sources.mark_used(ids, SystemTime::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_id_roundtrips_through_u8_hrp_and_cap26() {
+        for network_id in NetworkID::ALL {
+            assert_eq!(NetworkID::try_from(u8::from(network_id)), Ok(network_id));
+            assert_eq!(
+                NetworkID::from_hrp_suffix(network_id.hrp_suffix()),
+                Ok(network_id)
+            );
+            assert_eq!(
+                NetworkID::from_cap26_value(network_id.cap26_value()),
+                Ok(network_id)
+            );
+        }
+        assert_eq!(u8::from(NetworkID::Stokenet), 2);
+        assert_eq!(NetworkID::Simulator.hrp_suffix(), "sim");
+    }
+
+    #[test]
+    fn unknown_network_id_is_an_error() {
+        assert_eq!(
+            NetworkID::try_from(0x03),
+            Err("Unknown network ID: 3".to_owned())
+        );
+        assert!(NetworkID::from_cap26_value(0x101).is_err());
+        assert!(NetworkID::from_hrp_suffix("tdx_3_").is_err());
+    }
+
+    fn factor_source_of_kind(kind: FactorSourceKind) -> FactorSource {
+        FactorSource::new(FactorSourceIDFromHash {
+            public_key_hash: PublicKeyHash::from_hex(hex::encode([kind as u8; 29])).unwrap(),
+            factor_source_kind: kind,
+        })
+    }
+
+    fn with_flags(factor_source: FactorSource, flags: &[FactorSourceFlag]) -> FactorSource {
+        let metadata = FactorSourceMetadata {
+            flags: flags.iter().copied().collect(),
+            ..factor_source.metadata.clone()
+        };
+        factor_source.with_metadata(metadata)
+    }
+
+    #[test]
+    fn default_factor_source_is_main_device_else_by_preference() {
+        let main = with_flags(
+            factor_source_of_kind(FactorSourceKind::Device),
+            &[FactorSourceFlag::Main],
+        );
+        let ledger = factor_source_of_kind(FactorSourceKind::Ledger);
+        let arculus = factor_source_of_kind(FactorSourceKind::ArculusCard);
+        let policy = FactorSourceSelectionPolicy::default();
+
+        let profile = Profile::new(
+            FactorSources::from_iter([ledger.clone(), main.clone()]),
+            IndexSet::new(),
+        );
+        assert_eq!(profile.default_factor_source(&policy), Ok(main.clone()));
+
+        let profile = Profile::new(
+            FactorSources::from_iter([
+                arculus.clone(),
+                ledger.clone(),
+                with_flags(
+                    main,
+                    &[FactorSourceFlag::Main, FactorSourceFlag::DeletedByUser],
+                ),
+            ]),
+            IndexSet::new(),
+        );
+        assert_eq!(profile.default_factor_source(&policy), Ok(ledger));
+        let arculus_first = FactorSourceSelectionPolicy {
+            preference_order: vec![FactorSourceKind::ArculusCard],
+            ..policy.clone()
+        };
+        assert_eq!(profile.default_factor_source(&arculus_first), Ok(arculus));
+    }
+
+    #[test]
+    fn default_factor_source_explains_why_nothing_fits() {
+        let profile = Profile::new(
+            FactorSources::just(factor_source_of_kind(FactorSourceKind::Ledger)),
+            IndexSet::new(),
+        );
+        let silent = FactorSourceSelectionPolicy {
+            require_silent_derivation: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            profile.default_factor_source(&silent),
+            Err("No factor source can derive silently".to_owned())
+        );
+        let device_only = FactorSourceSelectionPolicy {
+            preference_order: vec![FactorSourceKind::Device],
+            ..Default::default()
+        };
+        assert_eq!(
+            profile.default_factor_source(&device_only),
+            Err("No factor source of kind [Device]".to_owned())
+        );
+        assert_eq!(
+            Profile::default().default_factor_source(&device_only),
+            Err("No factor source".to_owned())
+        );
+    }
+
+    #[test]
+    fn matrix_with_factor_source_in_disallowed_role_is_invalid() {
+        let device = factor_source_of_kind(FactorSourceKind::Device);
+        let trusted_contact = factor_source_of_kind(FactorSourceKind::TrustedContact);
+        let password = factor_source_of_kind(FactorSourceKind::Password);
+
+        assert_eq!(
+            MatrixOfFactorSources::new(vec![device.clone()], 1, vec![trusted_contact.clone()])
+                .validate(),
+            Ok(())
+        );
+        assert_eq!(
+            MatrixOfFactorSources::new(vec![trusted_contact], 1, vec![]).validate(),
+            Err("TrustedContact is not allowed in the threshold role".to_owned())
+        );
+        assert_eq!(
+            MatrixOfFactorSources::new(vec![device], 1, vec![password]).validate(),
+            Err("Password is not allowed in the override role".to_owned())
+        );
+    }
+
+    #[test]
+    fn matrix_which_can_never_sign_is_invalid() {
+        let device = factor_source_of_kind(FactorSourceKind::Device);
+        let ledger = factor_source_of_kind(FactorSourceKind::Ledger);
+
+        assert_eq!(
+            MatrixOfFactorSources::new(vec![], 0, vec![]).validate(),
+            Err("Matrix has no factor sources".to_owned())
+        );
+        assert_eq!(
+            MatrixOfFactorSources::new(vec![device.clone()], 2, vec![ledger.clone()]).validate(),
+            Err("Threshold 2 exceeds the 1 threshold factors".to_owned())
+        );
+        assert_eq!(
+            MatrixOfFactorSources::new(vec![], 0, vec![ledger]).validate(),
+            Ok(())
+        );
+        assert_eq!(
+            MatrixOfFactorSources::new(vec![device], 1, vec![]).validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn factor_sources_insert_remove_and_filter_by_kind() {
+        let device = factor_source_of_kind(FactorSourceKind::Device);
+        let ledger = factor_source_of_kind(FactorSourceKind::Ledger);
+        let veci = FactorInstanceInUnsecurifiedSpace::new(
+            crate::poly_derive::test_helpers::account_instance(&device, KeySpace::Unsecurified, 0),
+        );
+        let mut profile = Profile::new(
+            FactorSources::just(device.clone()),
+            IndexSet::from_iter([Account::new_unsecurified(veci, NetworkID::Mainnet)]),
+        );
+
+        assert!(profile.add_factor_source(device.clone()).is_err());
+        assert_eq!(profile.add_factor_source(ledger.clone()), Ok(()));
+        assert_eq!(
+            profile.factor_sources.get(&ledger.factor_source_id),
+            Some(&ledger)
+        );
+        assert_eq!(
+            profile
+                .factor_sources
+                .of_kind(FactorSourceKind::Device)
+                .collect_vec(),
+            vec![&device]
+        );
+
+        assert!(profile
+            .remove_factor_source(&device.factor_source_id)
+            .is_err());
+        assert_eq!(
+            profile.remove_factor_source(&ledger.factor_source_id),
+            Ok(ledger.clone())
+        );
+        assert!(profile
+            .remove_factor_source(&ledger.factor_source_id)
+            .is_err());
+        assert_eq!(profile.factor_sources.iter().collect_vec(), vec![&device]);
+    }
+
+    #[test]
+    fn recovered_matrix_keeps_key_hashes_of_underived_instances() {
+        use crate::poly_derive::test_helpers::*;
+
+        let device = factor_source();
+        let ledger = factor_source_with(2, FactorSourceKind::Ledger);
+        let arculus = factor_source_with(3, FactorSourceKind::ArculusCard);
+        let key = |f: &FactorSource| account_instance(f, KeySpace::Securified, 0);
+        let hash = |f: &FactorSource| PublicKeyHash::new(key(f));
+        let access_controller = OnChainAccessController {
+            entity_address: AccountAddress::new(
+                account_instance(&device, KeySpace::Unsecurified, 0),
+                NetworkID::Mainnet,
+            )
+            .into(),
+            threshold: 2,
+            threshold_key_hashes: vec![hash(&device), hash(&ledger)],
+            override_key_hashes: vec![hash(&arculus)],
+        };
+
+        let recovered = RecoveredMatrixOfFactorInstances::from_access_controller(
+            &access_controller,
+            &FactorInstances::from([key(&device), key(&arculus)]),
+        );
+        assert!(!recovered.is_complete());
+        assert_eq!(
+            recovered.matrix,
+            MatrixOfFactorInstances::new(
+                vec![FactorInstanceInSecurifiedSpace::new(key(&device))],
+                2,
+                vec![FactorInstanceInSecurifiedSpace::new(key(&arculus))],
+            )
+        );
+        assert_eq!(
+            recovered.missing,
+            MatrixOfPublicKeyHashes::new(vec![hash(&ledger)], 2, vec![])
+        );
+
+        let recovered = RecoveredMatrixOfFactorInstances::from_access_controller(
+            &access_controller,
+            &FactorInstances::from([key(&device), key(&ledger), key(&arculus)]),
+        );
+        assert!(recovered.is_complete());
+        assert_eq!(recovered.matrix.threshold_factors().len(), 2);
+    }
+
+    fn unsecurified_account(factor_source: &FactorSource, n: usize) -> Account {
+        Account::new_unsecurified(
+            FactorInstanceInUnsecurifiedSpace::new(
+                crate::poly_derive::test_helpers::account_instance(
+                    factor_source,
+                    KeySpace::Unsecurified,
+                    n,
+                ),
+            ),
+            NetworkID::Mainnet,
+        )
+    }
+
+    #[test]
+    fn insert_entities_inserts_none_if_any_address_is_taken() {
+        let device = factor_source_of_kind(FactorSourceKind::Device);
+        let alice = unsecurified_account(&device, 0);
+        let bob = unsecurified_account(&device, 1);
+        let mut profile = Profile::new(
+            FactorSources::just(device.clone()),
+            IndexSet::from_iter([alice.clone()]),
+        );
+
+        let mut alice_with_key = alice.clone();
+        alice_with_key.set_authentication_signing_key(
+            crate::poly_derive::test_helpers::account_instance(&device, KeySpace::Unsecurified, 2),
+        );
+        assert_eq!(
+            profile.insert_entities(IndexSet::from_iter([bob.clone(), alice_with_key])),
+            Err("Account already in Profile".to_owned())
+        );
+        let mut bob_with_key = bob.clone();
+        bob_with_key.set_authentication_signing_key(
+            crate::poly_derive::test_helpers::account_instance(&device, KeySpace::Unsecurified, 2),
+        );
+        assert_eq!(
+            profile.insert_entities(IndexSet::from_iter([bob.clone(), bob_with_key])),
+            Err("Account already in Profile".to_owned())
+        );
+        assert_eq!(
+            profile.network(NetworkID::Mainnet).accounts,
+            IndexSet::<_>::from_iter([alice.clone()])
+        );
+
+        assert_eq!(
+            profile.insert_entities(IndexSet::from_iter([bob.clone()])),
+            Ok(())
+        );
+        assert_eq!(
+            profile.network(NetworkID::Mainnet).accounts,
+            IndexSet::<_>::from_iter([alice, bob])
+        );
+    }
+
+    #[test]
+    fn insert_recovered_entities_replaces_unsecurified_with_securified() {
+        use crate::poly_derive::test_helpers::*;
+
+        let device = factor_source();
+        let auth_key = account_instance(&device, KeySpace::Unsecurified, 5);
+        let mut alice = unsecurified_account(&device, 0);
+        alice.set_authentication_signing_key(auth_key.clone());
+        let bob = unsecurified_account(&device, 1);
+        let carol = unsecurified_account(&device, 2);
+        let mut profile = Profile::new(
+            FactorSources::just(device.clone()),
+            IndexSet::from_iter([alice.clone(), bob.clone()]),
+        );
+        let matrix = MatrixOfFactorInstances::new(
+            vec![FactorInstanceInSecurifiedSpace::new(account_instance(
+                &device,
+                KeySpace::Securified,
+                0,
+            ))],
+            1,
+            vec![],
+        );
+        let recovered_alice = SecurifiedEntity {
+            address: alice.address(),
+            veci: None,
+            matrix: matrix.clone(),
+            missing_key_hashes: MatrixOfPublicKeyHashes::new(vec![], 1, vec![]),
+            authentication_signing_key: None,
+        };
+
+        assert_eq!(
+            profile.insert_recovered_entities(IndexSet::from_iter([
+                Account::from(recovered_alice.clone()),
+                bob.clone(),
+                carol.clone(),
+            ])),
+            Ok(())
+        );
+
+        let alice = alice.as_unsecurified_entity().unwrap().clone();
+        assert_eq!(
+            profile.network(NetworkID::Mainnet).accounts,
+            IndexSet::<_>::from_iter([
+                Account::from(SecurifiedEntity::securifying(alice, matrix)),
+                bob,
+                carol,
+            ])
+        );
+    }
+
+    #[test]
+    fn insert_recovered_entities_changes_nothing_if_address_recovered_twice() {
+        let device = factor_source_of_kind(FactorSourceKind::Device);
+        let alice = unsecurified_account(&device, 0);
+        let mut alice_with_key = alice.clone();
+        alice_with_key.set_authentication_signing_key(
+            crate::poly_derive::test_helpers::account_instance(&device, KeySpace::Unsecurified, 2),
+        );
+        let mut profile = Profile::new(FactorSources::just(device.clone()), IndexSet::new());
+
+        assert_eq!(
+            profile.insert_recovered_entities(IndexSet::from_iter([
+                unsecurified_account(&device, 1),
+                alice,
+                alice_with_key
+            ])),
+            Err("Account recovered twice".to_owned())
+        );
+        assert!(profile.network(NetworkID::Mainnet).accounts.is_empty());
+    }
 }
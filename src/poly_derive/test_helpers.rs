@@ -0,0 +1,124 @@
+use crate::prelude::*;
+
+/// Derives deterministic "public keys", hashing the derivation path.
+pub(crate) struct TestDerivationInteractor;
+
+#[async_trait]
+impl DerivationInteractors for TestDerivationInteractor {
+    async fn derive(
+        &self,
+        request: FactorSourceDerivationRequest,
+    ) -> FactorSourceDerivationOutcome {
+        FactorSourceDerivationOutcome::Derived(
+            request
+                .derivation_paths
+                .into_iter()
+                .map(instance_at)
+                .collect(),
+        )
+    }
+}
+
+/// Derives like `TestDerivationInteractor`, except that the user skips
+/// every factor source of kind `0`, e.g. a Ledger not at hand.
+pub(crate) struct SkipsKind(pub(crate) FactorSourceKind);
+
+#[async_trait]
+impl DerivationInteractors for SkipsKind {
+    async fn derive(
+        &self,
+        request: FactorSourceDerivationRequest,
+    ) -> FactorSourceDerivationOutcome {
+        if request.factor_source_kind() == self.0 {
+            return FactorSourceDerivationOutcome::Skipped;
+        }
+        TestDerivationInteractor.derive(request).await
+    }
+}
+
+/// Derives like `TestDerivationInteractor`, recording the factor sources
+/// of every `derive` and `derive_many` call.
+#[derive(Default)]
+pub(crate) struct RecordingInteractor {
+    derive_calls: RwLock<Vec<FactorSourceIDFromHash>>,
+    derive_many_calls: RwLock<Vec<Vec<FactorSourceIDFromHash>>>,
+}
+impl RecordingInteractor {
+    pub(crate) fn derive_calls(&self) -> Vec<FactorSourceIDFromHash> {
+        self.derive_calls.read().unwrap().clone()
+    }
+    pub(crate) fn derive_many_calls(&self) -> Vec<Vec<FactorSourceIDFromHash>> {
+        self.derive_many_calls.read().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl DerivationInteractors for RecordingInteractor {
+    async fn derive(
+        &self,
+        request: FactorSourceDerivationRequest,
+    ) -> FactorSourceDerivationOutcome {
+        self.derive_calls
+            .write()
+            .unwrap()
+            .push(request.factor_source.factor_source_id.clone());
+        TestDerivationInteractor.derive(request).await
+    }
+
+    async fn derive_many(
+        &self,
+        requests: Vec<FactorSourceDerivationRequest>,
+    ) -> IndexMap<FactorSourceIDFromHash, FactorSourceDerivationOutcome> {
+        self.derive_many_calls.write().unwrap().push(
+            requests
+                .iter()
+                .map(|r| r.factor_source.factor_source_id.clone())
+                .collect(),
+        );
+        TestDerivationInteractor.derive_many(requests).await
+    }
+}
+
+pub(crate) fn instance_at(path: DerivationPath) -> FactorInstance {
+    let digest = hex::decode(sha256::digest(format!("{:?}", path))).unwrap();
+    FactorInstance::new(
+        path,
+        PublicKey {
+            bytes: digest.try_into().unwrap(),
+        },
+    )
+}
+
+pub(crate) fn factor_source_with(byte: u8, kind: FactorSourceKind) -> FactorSource {
+    FactorSource::new(FactorSourceIDFromHash {
+        public_key_hash: PublicKeyHash::from_hex(hex::encode([byte; 29])).unwrap(),
+        factor_source_kind: kind,
+    })
+}
+
+pub(crate) fn factor_source() -> FactorSource {
+    factor_source_with(1, FactorSourceKind::Device)
+}
+
+pub(crate) fn account_request(
+    factor_source: &FactorSource,
+    key_space: KeySpace,
+) -> DerivationRequestInKeySpace {
+    DerivationRequestInKeySpace::new(
+        factor_source.factor_source_id.clone(),
+        NetworkID::Mainnet,
+        CAP26EntityKind::Account,
+        CAP26KeyKind::T9n,
+        key_space,
+    )
+}
+
+/// Account instance at the `n`:th index of `key_space`.
+pub(crate) fn account_instance(
+    factor_source: &FactorSource,
+    key_space: KeySpace,
+    n: usize,
+) -> FactorInstance {
+    let index = std::iter::Step::forward(CAP26Index::first_in(key_space), n);
+    instance_at(account_request(factor_source, key_space).derivation_path_at(index))
+}